@@ -0,0 +1,177 @@
+use std::fmt;
+
+use crate::{EntityId, EntityKind, Entities};
+
+/// Structure of the flow graph formed by `upstream`/`downstream` links.
+#[derive(Debug, Default)]
+pub struct Analysis {
+    /// Groups of entities that feed each other in a loop.
+    pub cycles: Vec<Vec<EntityId>>,
+    /// Groups of entities linked to each other in either direction.
+    pub components: Vec<Vec<EntityId>>,
+    /// Entities that take items but have no source anywhere upstream.
+    pub unsupplied: Vec<EntityId>,
+    /// Every entity after all of its suppliers, or `None` if there is a cycle.
+    pub order: Option<Vec<EntityId>>,
+}
+
+impl Analysis {
+    pub fn run(entities: &Entities) -> Self {
+        let live: Vec<usize> = entities.live().collect();
+        let ids = |group: Vec<usize>| group.into_iter().map(|i| entities.id(i)).collect();
+        Self {
+            cycles: strongly_connected(entities, &live).into_iter()
+                .filter(|group| group.len() > 1 || entities.downstream[group[0]].contains(&group[0]))
+                .map(ids)
+                .collect(),
+            components: weakly_connected(entities, &live).into_iter().map(ids).collect(),
+            unsupplied: ids(unsupplied(entities, &live)),
+            order: topological_order(entities, &live).map(ids),
+        }
+    }
+}
+
+/// Kosaraju's algorithm, with explicit stacks so long chains cannot overflow the call stack.
+fn strongly_connected(entities: &Entities, live: &[usize]) -> Vec<Vec<usize>> {
+    let len = entities.position.len();
+    let mut visited = vec![false; len];
+    let mut finished = Vec::with_capacity(live.len());
+    for &start in live {
+        if visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        let mut stack = vec![(start, 0)];
+        while let Some((i, next)) = stack.pop() {
+            match entities.downstream[i].get(next) {
+                Some(&d) => {
+                    stack.push((i, next + 1));
+                    if !visited[d] {
+                        visited[d] = true;
+                        stack.push((d, 0));
+                    }
+                }
+                None => finished.push(i),
+            }
+        }
+    }
+
+    let mut assigned = vec![false; len];
+    let mut groups = Vec::new();
+    for &start in finished.iter().rev() {
+        if assigned[start] {
+            continue;
+        }
+        assigned[start] = true;
+        let mut group = Vec::new();
+        let mut stack = vec![start];
+        while let Some(i) = stack.pop() {
+            group.push(i);
+            for &u in &entities.upstream[i] {
+                if !assigned[u] {
+                    assigned[u] = true;
+                    stack.push(u);
+                }
+            }
+        }
+        group.sort_unstable();
+        groups.push(group);
+    }
+    groups
+}
+
+fn weakly_connected(entities: &Entities, live: &[usize]) -> Vec<Vec<usize>> {
+    let mut seen = vec![false; entities.position.len()];
+    let mut groups = Vec::new();
+    for &start in live {
+        if seen[start] {
+            continue;
+        }
+        seen[start] = true;
+        let mut group = Vec::new();
+        let mut stack = vec![start];
+        while let Some(i) = stack.pop() {
+            group.push(i);
+            for &j in entities.upstream[i].iter().chain(&entities.downstream[i]) {
+                if !seen[j] {
+                    seen[j] = true;
+                    stack.push(j);
+                }
+            }
+        }
+        group.sort_unstable();
+        groups.push(group);
+    }
+    groups
+}
+
+fn unsupplied(entities: &Entities, live: &[usize]) -> Vec<usize> {
+    let mut supplied = vec![false; entities.position.len()];
+    let mut stack: Vec<usize> = live.iter().copied()
+        .filter(|&i| matches!(entities.kind[i], EntityKind::Source { .. }))
+        .collect();
+    for &i in &stack {
+        supplied[i] = true;
+    }
+    while let Some(i) = stack.pop() {
+        for &d in &entities.downstream[i] {
+            if !supplied[d] {
+                supplied[d] = true;
+                stack.push(d);
+            }
+        }
+    }
+    live.iter().copied()
+        .filter(|&i| !supplied[i] && !matches!(entities.kind[i], EntityKind::Structure))
+        .collect()
+}
+
+/// Kahn's algorithm.
+fn topological_order(entities: &Entities, live: &[usize]) -> Option<Vec<usize>> {
+    let mut pending: Vec<usize> = entities.upstream.iter().map(Vec::len).collect();
+    let mut ready: Vec<usize> = live.iter().copied().filter(|&i| pending[i] == 0).collect();
+    ready.reverse();
+    let mut order = Vec::with_capacity(live.len());
+    while let Some(i) = ready.pop() {
+        order.push(i);
+        for &d in &entities.downstream[i] {
+            pending[d] -= 1;
+            if pending[d] == 0 {
+                ready.push(d);
+            }
+        }
+    }
+    (order.len() == live.len()).then_some(order)
+}
+
+fn write_group(f: &mut fmt::Formatter, group: &[EntityId]) -> fmt::Result {
+    let names: Vec<String> = group.iter().map(EntityId::to_string).collect();
+    write!(f, "[{}]", names.join(", "))
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Components: {}", self.components.len())?;
+        for group in &self.components {
+            write!(f, "  ")?;
+            write_group(f, group)?;
+            writeln!(f)?;
+        }
+        writeln!(f, "Cycles: {}", self.cycles.len())?;
+        for group in &self.cycles {
+            write!(f, "  ")?;
+            write_group(f, group)?;
+            writeln!(f)?;
+        }
+        write!(f, "Unsupplied: ")?;
+        write_group(f, &self.unsupplied)?;
+        writeln!(f)?;
+        match &self.order {
+            Some(order) => {
+                write!(f, "Topological order: ")?;
+                write_group(f, order)
+            }
+            None => write!(f, "Topological order: none (the graph has a cycle)"),
+        }
+    }
+}
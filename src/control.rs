@@ -0,0 +1,45 @@
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// A runtime control for a running world. At the terminal each is typed as a line on stdin:
+/// `p` pauses or resumes, `s` steps one tick while paused, `+` and `-` double or halve the
+/// speed, and a number such as `0.25` or `8` sets the speed multiplier directly.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Control {
+    TogglePause,
+    Step,
+    Faster,
+    Slower,
+    Speed(f64),
+}
+
+impl Control {
+    pub fn parse(line: &str) -> Option<Self> {
+        match line.trim() {
+            "p" | "pause" => Some(Self::TogglePause),
+            "s" | "step" => Some(Self::Step),
+            "+" | "faster" => Some(Self::Faster),
+            "-" | "slower" => Some(Self::Slower),
+            other => other.trim_end_matches('x').parse().ok()
+                .filter(|speed: &f64| speed.is_finite())
+                .map(Self::Speed),
+        }
+    }
+}
+
+/// Reads controls from stdin on a background thread. Lines that are not controls are ignored.
+pub fn spawn_reader() -> Receiver<Control> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if let Some(control) = Control::parse(&line) {
+                if sender.send(control).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    receiver
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+
+use crate::EntityId;
+use crate::resource::Resource;
+
+/// Something that happened to an entity during a tick.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// An entity with room for what it wants received none of it, or an assembler had power
+    /// but not the ingredients to start a craft.
+    Starved { entity: EntityId },
+    /// An entity is full: it could not put out what it made, or had no room for what its
+    /// suppliers still hold.
+    Blocked { entity: EntityId },
+    Produced { entity: EntityId, resource: Resource },
+    Consumed { entity: EntityId, resource: Resource },
+    Placed { entity: EntityId },
+    Removed { entity: EntityId },
+}
+
+impl Event {
+    pub fn entity(&self) -> EntityId {
+        match *self {
+            Self::Starved { entity }
+            | Self::Blocked { entity }
+            | Self::Produced { entity, .. }
+            | Self::Consumed { entity, .. }
+            | Self::Placed { entity }
+            | Self::Removed { entity } => entity,
+        }
+    }
+
+    /// Whether the event points at an entity that is stuck.
+    pub fn is_alert(&self) -> bool {
+        matches!(self, Self::Starved { .. } | Self::Blocked { .. })
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Starved { entity } => write!(f, "entity {entity} is starved"),
+            Self::Blocked { entity } => write!(f, "entity {entity} is blocked"),
+            Self::Produced { entity, resource } =>
+                write!(f, "entity {entity} produced {} of resource #{}", resource.quantity, resource.id.0),
+            Self::Consumed { entity, resource } =>
+                write!(f, "entity {entity} consumed {} of resource #{}", resource.quantity, resource.id.0),
+            Self::Placed { entity } => write!(f, "entity {entity} was placed"),
+            Self::Removed { entity } => write!(f, "entity {entity} was removed"),
+        }
+    }
+}
+
+type Subscriber = Box<dyn FnMut(u64, &[Event])>;
+
+/// Collects events as they happen and hands each tick's batch to every subscriber.
+#[derive(Default)]
+pub struct EventBus {
+    pending: Vec<Event>,
+    last: Vec<Event>,
+    subscribers: Vec<Subscriber>,
+}
+
+impl EventBus {
+    pub fn emit(&mut self, event: Event) {
+        self.pending.push(event);
+    }
+
+    /// Calls `subscriber` once per tick with the tick number and the events since the
+    /// previous tick, including placements and removals made between ticks.
+    pub fn subscribe(&mut self, subscriber: impl FnMut(u64, &[Event]) + 'static) {
+        self.subscribers.push(Box::new(subscriber));
+    }
+
+    /// Delivers the pending events to every subscriber and keeps them as `last`.
+    pub fn flush(&mut self, tick: u64) {
+        self.last = std::mem::take(&mut self.pending);
+        for subscriber in &mut self.subscribers {
+            subscriber(tick, &self.last);
+        }
+    }
+
+    /// The events delivered by the most recent `flush`.
+    pub fn last(&self) -> &[Event] {
+        &self.last
+    }
+}
+
+impl fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EventBus")
+            .field("pending", &self.pending)
+            .field("last", &self.last)
+            .field("subscribers", &self.subscribers.len())
+            .finish()
+    }
+}
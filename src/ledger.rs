@@ -0,0 +1,57 @@
+use std::fmt;
+
+use crate::resource::{Inventory, ResourceId};
+
+/// Where every unit went during one tick of `Entities::update`.
+#[derive(Debug, Clone, Default)]
+pub struct Ledger {
+    /// Created by sources and assembler outputs.
+    pub produced: Inventory,
+    /// Destroyed by sinks and assembler inputs.
+    pub consumed: Inventory,
+    /// Moved between entities.
+    pub transferred: Inventory,
+    /// Dropped because a stack saturated at the quantity type's maximum.
+    pub lost: Inventory,
+}
+
+impl Ledger {
+    /// Checks that `after` is exactly `before` plus what was produced, minus what was consumed
+    /// or lost. Returns the first resource kind that does not balance.
+    pub fn reconcile(&self, before: &Inventory, after: &Inventory) -> Result<(), Imbalance> {
+        let ids = before.iter().chain(after.iter()).chain(self.produced.iter()).map(|r| r.id);
+        for id in ids {
+            let expected = (before.get(id) as i64) + (self.produced.get(id) as i64)
+                - (self.consumed.get(id) as i64) - (self.lost.get(id) as i64);
+            let actual = after.get(id) as i64;
+            if expected != actual {
+                return Err(Imbalance { id, expected, actual });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Ledger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Produced: {}\tConsumed: {}\tTransferred: {}\tLost: {}",
+               self.produced.total(),
+               self.consumed.total(),
+               self.transferred.total(),
+               self.lost.total())
+    }
+}
+
+/// A resource kind whose stock changed by something other than what the ledger recorded.
+#[derive(Debug)]
+pub struct Imbalance {
+    pub id: ResourceId,
+    pub expected: i64,
+    pub actual: i64,
+}
+
+impl fmt::Display for Imbalance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "resource #{} should total {} but totals {}", self.id.0, self.expected, self.actual)
+    }
+}
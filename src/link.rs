@@ -0,0 +1,63 @@
+use std::collections::VecDeque;
+
+use crate::resource::{Quantity, Resource};
+
+/// A connection between two entities, such as a belt, that limits how fast items enter it and
+/// how long they take to arrive. Links without one of these are instant and unlimited.
+#[derive(Debug, Clone)]
+pub struct Link {
+    /// Most units that may enter the link per tick; `None` for no limit.
+    pub rate: Option<Quantity>,
+    /// Ticks between an item entering the link and arriving downstream.
+    pub latency: u32,
+    /// Items on their way, with the tick each arrives on, oldest first.
+    in_flight: VecDeque<(u64, Resource)>,
+    sent: Quantity,
+    sent_tick: u64,
+}
+
+impl Link {
+    pub fn new(rate: Option<Quantity>, latency: u32) -> Self {
+        Self { rate, latency, in_flight: VecDeque::new(), sent: 0, sent_tick: 0 }
+    }
+
+    /// Units that may still enter the link on `tick`.
+    pub fn allowance(&self, tick: u64) -> Quantity {
+        match self.rate {
+            Some(rate) if self.sent_tick == tick => rate.saturating_sub(self.sent),
+            Some(rate) => rate,
+            None => Quantity::MAX,
+        }
+    }
+
+    /// Records `resource` entering the link on `tick`. Items on a link without latency are
+    /// delivered by the caller straight away and only count against the rate.
+    pub fn send(&mut self, resource: Resource, tick: u64) {
+        if self.sent_tick != tick {
+            self.sent_tick = tick;
+            self.sent = 0;
+        }
+        self.sent += resource.quantity;
+        if self.latency > 0 && resource.quantity > 0 {
+            self.in_flight.push_back((tick + self.latency as u64, resource));
+        }
+    }
+
+    /// Removes and returns everything due to arrive by `tick`.
+    pub fn arrivals(&mut self, tick: u64) -> Vec<Resource> {
+        let mut arrived = Vec::new();
+        while let Some(&(due, resource)) = self.in_flight.front() {
+            if due > tick {
+                break;
+            }
+            arrived.push(resource);
+            self.in_flight.pop_front();
+        }
+        arrived
+    }
+
+    /// Removes and returns everything still on the link.
+    pub fn drain(&mut self) -> Vec<Resource> {
+        self.in_flight.drain(..).map(|(_, resource)| resource).collect()
+    }
+}
@@ -1,168 +1,1403 @@
-use std::{thread, time};
-use std::ops::{Add, AddAssign, Sub, SubAssign};
+mod analysis;
+mod control;
+mod event;
+mod ledger;
+mod link;
+mod power;
+mod recipe;
+mod resource;
+mod rng;
+mod spatial;
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::rc::Rc;
+use std::{fmt, process, thread, time};
+
+use analysis::Analysis;
+use control::Control;
+use event::{Event, EventBus};
+use ledger::Ledger;
+use link::Link;
+use power::{PowerGrid, PowerRole};
+use recipe::Recipe;
+use rng::Rng;
+use resource::{Inventory, Quantity, Registry, Resource, ResourceError, ResourceFilter, ResourceId};
+use spatial::SpatialIndex;
 
 const ESC: char = 27 as char;
+const RESOURCE_DEFINITIONS: &str = "resources.def";
+/// How many stacks of each resource a storage entity holds.
+const STORAGE_STACKS: Quantity = 16;
+/// How many batches of each ingredient an assembler buffers.
+const ASSEMBLER_BATCHES: Quantity = 2;
+/// Total units an entity holds across all resources unless its kind says otherwise.
+const DEFAULT_CAPACITY: Quantity = 256;
+/// Most simulation ticks, beyond one frame's worth, run between two frames before the loop
+/// gives up catching up.
+const MAX_CATCH_UP_TICKS: u32 = 8;
+/// Slowest and fastest speed multipliers a running world accepts.
+const MIN_SPEED: f64 = 0.25;
+const MAX_SPEED: f64 = 64.0;
+/// Seed for the world's random generator unless `--seed` gives another.
+const DEFAULT_SEED: u64 = 0x5eed;
+
+type Position = (isize, isize);
+
+/// The side an entity outputs to; it takes input from the opposite side.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Direction {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Direction {
+    const ALL: [Direction; 4] = [Self::North, Self::East, Self::South, Self::West];
+
+    fn clockwise(self) -> Self {
+        match self {
+            Self::North => Self::East,
+            Self::East => Self::South,
+            Self::South => Self::West,
+            Self::West => Self::North,
+        }
+    }
+
+    fn opposite(self) -> Self {
+        self.clockwise().clockwise()
+    }
 
-#[derive(Debug, Copy, Clone, PartialOrd, PartialEq)]
-enum Resource{
-    A(u8),
-    B(u8),
+    fn arrow(self) -> char {
+        match self {
+            Self::North => '^',
+            Self::East => '>',
+            Self::South => 'v',
+            Self::West => '<',
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum EntityKind {
+    /// Generates `produces` every tick.
+    Source { produces: Resource },
+    /// Consumes everything it receives, counting it as delivered.
+    Sink,
+    /// Holds `STORAGE_STACKS` stacks of each resource it wants.
+    Storage,
+    /// Passes along up to `rate` units per tick of whatever its upstream offers.
+    Conveyor { rate: Quantity },
+    Assembler(Recipe),
+    /// Holds no items; placed for its power role, e.g. poles and generators.
+    Structure,
+}
+
+impl fmt::Display for EntityKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Source { produces } => write!(f, "Source({}×#{})", produces.quantity, produces.id.0),
+            Self::Sink => write!(f, "Sink"),
+            Self::Storage => write!(f, "Storage"),
+            Self::Conveyor { rate } => write!(f, "Conveyor({rate}/tick)"),
+            Self::Assembler(recipe) => write!(f, "Assembler({})", recipe.name),
+            Self::Structure => write!(f, "Structure"),
+        }
+    }
 }
 
-impl Add for Resource {
-    type Output = Self;
+impl EntityKind {
+    /// Whether downstream entities may take `id` from an entity of this kind. Assemblers only
+    /// give up their products so their ingredients are not pulled back out.
+    fn offers(&self, id: ResourceId) -> bool {
+        match self {
+            Self::Assembler(recipe) => recipe.outputs.get(id) > 0,
+            _ => true,
+        }
+    }
+
+    /// Total units across all resources a new entity of this kind holds.
+    fn default_capacity(&self) -> Quantity {
+        match self {
+            Self::Storage => DEFAULT_CAPACITY * STORAGE_STACKS,
+            Self::Conveyor { rate } => *rate,
+            Self::Structure => 0,
+            _ => DEFAULT_CAPACITY,
+        }
+    }
+
+    /// What a new entity of this kind accepts. Assemblers take only their ingredients.
+    fn default_filter(&self) -> ResourceFilter {
+        match self {
+            Self::Assembler(recipe) => ResourceFilter::Only(recipe.inputs.iter().map(|r| r.id).collect()),
+            _ => ResourceFilter::Any,
+        }
+    }
+
+    /// Capacity held back from incoming transfers. Assemblers keep room for one batch of
+    /// products so a full load of ingredients cannot deadlock them.
+    fn reserved(&self) -> Quantity {
+        match self {
+            Self::Assembler(recipe) => recipe.outputs.total(),
+            _ => 0,
+        }
+    }
 
-    fn add(self, other: Self) -> Self {
-        match (self, other) {
-            (Self::A(s), Self::A(o)) => Self::A(s.saturating_add(o)),
-            (Self::B(s), Self::B(o)) => Self::B(s.saturating_add(o)),
-            _ => panic!("Cannot mix Resource variants"),
+    /// The most of `id` an entity of this kind will hold.
+    fn limit(&self, registry: &Registry, id: ResourceId) -> Quantity {
+        let stack_size = registry.get(id).stack_size;
+        match self {
+            Self::Storage => stack_size.saturating_mul(STORAGE_STACKS),
+            Self::Conveyor { rate } => *rate,
+            Self::Assembler(recipe) if recipe.inputs.get(id) > 0 =>
+                recipe.inputs.get(id) * ASSEMBLER_BATCHES,
+            _ => stack_size,
         }
     }
 }
 
-impl AddAssign for Resource {
-    fn add_assign(&mut self, other: Self) {
-        *self = *self + other;
+/// A transfer between two entities that `Entities::update` could not carry out.
+#[derive(Debug)]
+struct TransferError {
+    from: usize,
+    to: usize,
+    error: ResourceError,
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "transfer {} -> {} failed: {}", self.from, self.to, self.error)
+    }
+}
+
+impl Error for TransferError {}
+
+/// ANSI color for a stockpile by quarter of capacity: red, yellow, green, then blue when full.
+fn fill_color(quantity: Quantity, capacity: Quantity) -> u8 {
+    let quarters = (quantity as u64 * 4) / capacity.max(1) as u64;
+    match quarters {
+        0 => 31,
+        1 => 33,
+        2 => 32,
+        _ => 34,
     }
 }
 
-impl Sub for Resource {
-    type Output = Self;
+/// How an entity shares a limited amount among several partners: a receiver pulling from
+/// several suppliers, or a supplier asked for more than it holds.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Distribution {
+    /// Partners are served fully in link order before the next gets anything.
+    Priority,
+    /// Like `Priority`, but the partner served first rotates every tick.
+    RoundRobin,
+    /// Every partner gets the same fraction of what it could give or asked for.
+    Proportional,
+}
 
-    fn sub(self, other: Self) -> Self {
-        match (self, other) {
-            (Self::A(s), Self::A(o)) => Self::A(s.saturating_sub(o)),
-            (Self::B(s), Self::B(o)) => Self::B(s.saturating_sub(o)),
-            _ => panic!("Cannot mix Resource variants"),
+/// Divides up to `total` among `partners`, each capped at its own amount, and returns each
+/// partner's share in the order given. `turn` rotates who goes first for round-robin.
+fn distribute(policy: Distribution, partners: &[(usize, Quantity)], total: Quantity, turn: u64) -> Vec<(usize, Quantity)> {
+    let mut shares: Vec<(usize, Quantity)> = partners.iter().map(|&(p, _)| (p, 0)).collect();
+    let capped: u64 = partners.iter().map(|&(_, cap)| cap as u64).sum();
+    let mut remaining = total as u64;
+    let mut order: Vec<usize> = (0..partners.len()).collect();
+    match policy {
+        Distribution::Priority => {}
+        Distribution::RoundRobin => order.rotate_left((turn % partners.len().max(1) as u64) as usize),
+        Distribution::Proportional if capped > remaining => {
+            for (share, &(_, cap)) in shares.iter_mut().zip(partners) {
+                share.1 = (cap as u64 * remaining / capped) as Quantity;
+            }
+            remaining -= shares.iter().map(|&(_, share)| share as u64).sum::<u64>();
+            // Hand out what rounding left over one unit at a time.
+            for (share, &(_, cap)) in shares.iter_mut().zip(partners) {
+                if remaining > 0 && share.1 < cap {
+                    share.1 += 1;
+                    remaining -= 1;
+                }
+            }
+            return shares;
         }
+        Distribution::Proportional => {}
     }
+    for k in order {
+        let give = (partners[k].1 as u64).min(remaining);
+        shares[k].1 = give as Quantity;
+        remaining -= give;
+    }
+    shares
+}
+
+/// How often a machine fails and how long it stays down.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Breakdown {
+    /// Probability of failing on any tick it is running: powered and, for an assembler,
+    /// partway through a craft.
+    chance: f64,
+    /// Ticks a failed machine stays stopped.
+    repair: u32,
 }
 
-impl SubAssign for Resource {
-    fn sub_assign(&mut self, other: Self) {
-        *self = *self - other;
+/// A planned movement of items between two entity slots.
+#[derive(Debug, Copy, Clone)]
+struct Transfer {
+    from: usize,
+    to: usize,
+    resource: Resource,
+}
+
+/// Mutably borrows two distinct elements of a slice.
+fn pair_mut<T>(slice: &mut [T], a: usize, b: usize) -> (&mut T, &mut T) {
+    assert_ne!(a, b);
+    if a < b {
+        let (left, right) = slice.split_at_mut(b);
+        (&mut left[a], &mut right[0])
+    } else {
+        let (left, right) = slice.split_at_mut(a);
+        (&mut right[0], &mut left[b])
     }
 }
 
-type Position = (isize, isize);
+/// A handle to an entity. The generation changes whenever a slot is reused, so a handle to a
+/// removed entity never refers to whatever was placed there afterwards.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct EntityId {
+    index: usize,
+    generation: u32,
+}
+
+impl fmt::Display for EntityId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+#[derive(Debug)]
+enum EntityError {
+    Unknown(EntityId),
+    /// The entity was removed; its slot may since have been reused.
+    Stale(EntityId),
+    SelfLink(EntityId),
+    NotLinked(EntityId, EntityId),
+}
+
+impl fmt::Display for EntityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unknown(id) => write!(f, "no entity {id}"),
+            Self::Stale(id) => write!(f, "entity {id} has been removed"),
+            Self::SelfLink(id) => write!(f, "entity {id} cannot feed itself"),
+            Self::NotLinked(from, to) => write!(f, "entity {from} does not feed entity {to}"),
+        }
+    }
+}
+
+impl Error for EntityError {}
+
+/// Why `Entities::place` refused a placement.
+#[derive(Debug)]
+enum PlacementError {
+    /// Part of the footprint lies outside the world.
+    OutOfBounds { placement: Placement, bounds: (usize, usize) },
+    /// `cell` is already covered by `occupant`.
+    Overlap { placement: Placement, cell: Position, occupant: EntityId },
+    /// The footprint covers no cells.
+    EmptyFootprint(Placement),
+}
+
+impl fmt::Display for PlacementError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::OutOfBounds { placement, bounds } =>
+                write!(f, "{:?} of size {:?} does not fit in a {}x{} world", placement.position, placement.size, bounds.0, bounds.1),
+            Self::Overlap { placement, cell, occupant } =>
+                write!(f, "{:?} of size {:?} overlaps entity {occupant} at {cell:?}", placement.position, placement.size),
+            Self::EmptyFootprint(placement) =>
+                write!(f, "{:?} has an empty footprint {:?}", placement.position, placement.size),
+        }
+    }
+}
+
+impl Error for PlacementError {}
+
+/// Where and how an entity sits on the map.
+#[derive(Debug, Copy, Clone)]
+struct Placement {
+    /// Top-left cell of the footprint.
+    position: Position,
+    facing: Direction,
+    /// Width and height in cells. Rotating an entity does not turn its footprint.
+    size: (usize, usize),
+}
+
+impl Placement {
+    fn new(position: Position, facing: Direction) -> Self {
+        Self { position, facing, size: (1, 1) }
+    }
+
+    fn with_size(self, width: usize, height: usize) -> Self {
+        Self { size: (width, height), ..self }
+    }
+}
+
+/// Every cell covered by a footprint of `size` anchored at `position`.
+fn footprint((x, y): Position, (width, height): (usize, usize)) -> impl Iterator<Item = Position> {
+    (0..height as isize).flat_map(move |dy| (0..width as isize).map(move |dx| (x + dx, y + dy)))
+}
+
+/// How `Entities::update` orders the transfers within a tick.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum UpdateMode {
+    /// Each entity pulls in slot order against the live state; an item can travel several hops
+    /// in one tick if the entities happen to be ordered along its path.
+    Sequential,
+    /// Every transfer is planned from the previous tick's state and applied together, so
+    /// results do not depend on slot order.
+    Buffered,
+}
+
+/// How `Entities::insert` wires a new entity into the flow graph.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ConnectionPolicy {
+    /// New entities are linked only through `connect`.
+    Manual,
+    /// A new entity feeds the neighbor it faces and is fed by neighbors facing it.
+    Adjacent,
+}
 
 #[derive(Debug)]
 struct Entities {
-    wants: Vec<Resource>,
-    has: Vec<Resource>,
+    generation: Vec<u32>,
+    alive: Vec<bool>,
+    /// Slots of removed entities, reused by `insert`.
+    free: Vec<usize>,
+    kind: Vec<EntityKind>,
+    wants: Vec<Inventory>,
+    has: Vec<Inventory>,
+    /// Total units an entity holds across all resources; transfers stop here.
+    capacity: Vec<Quantity>,
+    accepts: Vec<ResourceFilter>,
     position: Vec<Position>,
+    size: Vec<(usize, usize)>,
+    /// Which slot occupies each cell.
+    cells: SpatialIndex,
+    facing: Vec<Direction>,
     visible: Vec<bool>,
     upstream: Vec<Vec<usize>>,
     downstream: Vec<Vec<usize>>,
+    /// Links made by adjacency rather than `connect`, as (from, to) slots; rotating an entity
+    /// re-derives only these.
+    adjacent: HashSet<(usize, usize)>,
+    /// Pairs cut with `disconnect`, which adjacency never links again until `connect` is used.
+    severed: HashSet<(usize, usize)>,
+    /// Ticks left on the craft in progress, if any.
+    crafting: Vec<Option<f32>>,
+    /// Partial output a source has built up while running below full speed.
+    work: Vec<f32>,
+    power: Vec<PowerRole>,
+    /// Running total of what a sink has consumed.
+    delivered: Vec<Inventory>,
+    /// Units that reached or set off toward each entity in the current tick's transfers.
+    received: Vec<Quantity>,
+    /// How each entity fails, if it can.
+    breakdown: Vec<Option<Breakdown>>,
+    /// Ticks until a failed entity runs again; it works at zero speed until then.
+    broken: Vec<u32>,
+    /// Range a sink draws its units-per-tick demand from; `None` consumes everything.
+    demand: Vec<Option<(Quantity, Quantity)>>,
+    /// Items on their way to each entity over links with latency; counted against its capacity.
+    in_transit: Vec<Inventory>,
+    /// Rate and latency settings for links that are not instant, keyed by (from, to) slot.
+    /// Ordered so that deliveries, and so stack order, are the same on every run.
+    links: BTreeMap<(usize, usize), Link>,
+    /// How a supplier shares out its stock when asked for more than it has.
+    split: Vec<Distribution>,
+    /// How an entity spreads its pulls across its suppliers.
+    merge: Vec<Distribution>,
+    policy: ConnectionPolicy,
+    mode: UpdateMode,
+    /// Updates run so far; rotates round-robin distribution.
+    tick: u64,
+    /// Width and height of the area `place` accepts.
+    bounds: (usize, usize),
+    /// Power networks solved at the start of the last `update`.
+    grid: PowerGrid,
+    /// Unit flows recorded by the last `update`.
+    ledger: Ledger,
+    /// What happened to entities, handed to subscribers at the end of each `update`.
+    events: EventBus,
+    /// Assert after every transfer and every tick that no units appeared or vanished
+    /// unaccounted for.
+    audit: bool,
 }
 
 impl Entities {
-    fn new() -> Self {
+    fn new(bounds: (usize, usize)) -> Self {
         Self {
+            generation: Vec::with_capacity(1024),
+            alive: Vec::with_capacity(1024),
+            free: Vec::new(),
+            kind: Vec::with_capacity(1024),
             wants: Vec::with_capacity(1024),
             has: Vec::with_capacity(1024),
+            capacity: Vec::with_capacity(1024),
+            accepts: Vec::with_capacity(1024),
             position: Vec::with_capacity(1024),
+            size: Vec::with_capacity(1024),
+            cells: SpatialIndex::default(),
+            facing: Vec::with_capacity(1024),
             visible: Vec::with_capacity(1024),
             upstream: Vec::with_capacity(1024),
             downstream: Vec::with_capacity(1024),
+            adjacent: HashSet::new(),
+            severed: HashSet::new(),
+            crafting: Vec::with_capacity(1024),
+            work: Vec::with_capacity(1024),
+            power: Vec::with_capacity(1024),
+            delivered: Vec::with_capacity(1024),
+            received: Vec::with_capacity(1024),
+            breakdown: Vec::with_capacity(1024),
+            broken: Vec::with_capacity(1024),
+            demand: Vec::with_capacity(1024),
+            in_transit: Vec::with_capacity(1024),
+            links: BTreeMap::new(),
+            split: Vec::with_capacity(1024),
+            merge: Vec::with_capacity(1024),
+            policy: ConnectionPolicy::Adjacent,
+            mode: UpdateMode::Sequential,
+            tick: 0,
+            bounds,
+            grid: PowerGrid::default(),
+            ledger: Ledger::default(),
+            events: EventBus::default(),
+            audit: cfg!(debug_assertions),
         }
     }
 
-    fn insert(&mut self, wants: Resource, has: Resource, position: (isize, isize), visible: bool) {
-        let len = self.position.len();
-        let mut upstream = Vec::new();
-        let mut downstream = Vec::new();
-        let (x, y) = position;
-        for i in 0..len {
-            if self.position[i] == (x, y - 1) || // up
-                self.position[i] == (x - 1, y) {  // left
-                    upstream.push(i);
-                    self.downstream[i].push(len);
+    /// Checks a placement against the world bounds and existing entities, then inserts.
+    fn place(&mut self, kind: EntityKind, wants: Inventory, has: Inventory, placement: Placement, visible: bool) -> Result<EntityId, PlacementError> {
+        self.check_placement(placement)?;
+        Ok(self.insert(kind, wants, has, placement, visible))
+    }
+
+    fn check_placement(&self, placement: Placement) -> Result<(), PlacementError> {
+        let (width, height) = placement.size;
+        if width == 0 || height == 0 {
+            return Err(PlacementError::EmptyFootprint(placement));
+        }
+        let (x, y) = placement.position;
+        let in_bounds = x >= 0 && y >= 0
+            && (x as usize).checked_add(width).is_some_and(|right| right <= self.bounds.0)
+            && (y as usize).checked_add(height).is_some_and(|bottom| bottom <= self.bounds.1);
+        if !in_bounds {
+            return Err(PlacementError::OutOfBounds { placement, bounds: self.bounds });
+        }
+        for cell in footprint(placement.position, placement.size) {
+            if let Some(occupant) = self.at(cell) {
+                return Err(PlacementError::Overlap { placement, cell, occupant });
+            }
+        }
+        Ok(())
+    }
+
+    /// Inserts without validating the placement; overlapping an entity is a bug.
+    fn insert(&mut self, kind: EntityKind, wants: Inventory, has: Inventory, placement: Placement, visible: bool) -> EntityId {
+        debug_assert!(self.is_vacant(placement.position, placement.size),
+                      "{placement:?} overlaps another entity");
+        let i = match self.free.pop() {
+            Some(i) => i,
+            None => self.grow(),
+        };
+        self.alive[i] = true;
+        self.capacity[i] = kind.default_capacity();
+        self.accepts[i] = kind.default_filter();
+        self.kind[i] = kind;
+        self.wants[i] = wants;
+        self.has[i] = has;
+        self.position[i] = placement.position;
+        self.size[i] = placement.size;
+        for cell in footprint(placement.position, placement.size) {
+            self.cells.insert(cell, i);
+        }
+        self.facing[i] = placement.facing;
+        self.visible[i] = visible;
+        if self.policy == ConnectionPolicy::Adjacent {
+            self.connect_adjacent(i);
+        }
+        self.events.emit(Event::Placed { entity: self.id(i) });
+        self.id(i)
+    }
+
+    /// Adds an empty, dead slot to every column and returns its index.
+    fn grow(&mut self) -> usize {
+        self.generation.push(0);
+        self.alive.push(false);
+        self.kind.push(EntityKind::Structure);
+        self.wants.push(Inventory::new());
+        self.has.push(Inventory::new());
+        self.capacity.push(0);
+        self.accepts.push(ResourceFilter::Any);
+        self.position.push((0, 0));
+        self.size.push((1, 1));
+        self.facing.push(Direction::South);
+        self.visible.push(false);
+        self.upstream.push(Vec::new());
+        self.downstream.push(Vec::new());
+        self.crafting.push(None);
+        self.work.push(0.0);
+        self.power.push(PowerRole::None);
+        self.delivered.push(Inventory::new());
+        self.received.push(0);
+        self.breakdown.push(None);
+        self.broken.push(0);
+        self.demand.push(None);
+        self.in_transit.push(Inventory::new());
+        self.split.push(Distribution::Proportional);
+        self.merge.push(Distribution::Priority);
+        self.position.len() - 1
+    }
+
+    /// Removes an entity, unlinking it from its neighbors, and returns what it was holding.
+    fn remove(&mut self, id: EntityId) -> Result<Inventory, EntityError> {
+        let i = self.resolve(id)?;
+        for u in self.upstream[i].clone() {
+            self.unlink(u, i);
+        }
+        for d in self.downstream[i].clone() {
+            self.unlink(i, d);
+        }
+        self.severed.retain(|&(from, to)| from != i && to != i);
+        for cell in footprint(self.position[i], self.size[i]) {
+            self.cells.remove(cell, i);
+        }
+        self.alive[i] = false;
+        self.generation[i] += 1;
+        self.kind[i] = EntityKind::Structure;
+        self.wants[i] = Inventory::new();
+        self.capacity[i] = 0;
+        self.accepts[i] = ResourceFilter::Any;
+        self.visible[i] = false;
+        self.crafting[i] = None;
+        self.work[i] = 0.0;
+        self.power[i] = PowerRole::None;
+        self.delivered[i] = Inventory::new();
+        self.received[i] = 0;
+        self.breakdown[i] = None;
+        self.broken[i] = 0;
+        self.demand[i] = None;
+        self.split[i] = Distribution::Proportional;
+        self.merge[i] = Distribution::Priority;
+        self.free.push(i);
+        self.events.emit(Event::Removed { entity: id });
+        Ok(std::mem::take(&mut self.has[i]))
+    }
+
+    /// Whether a footprint of `size` at `position` covers only empty cells.
+    fn is_vacant(&self, position: Position, size: (usize, usize)) -> bool {
+        footprint(position, size).all(|cell| self.cells.get(cell).is_none())
+    }
+
+    /// The entity occupying `position`, if any.
+    fn at(&self, position: Position) -> Option<EntityId> {
+        self.cells.get(position).map(|i| self.id(i))
+    }
+
+    /// Slot indices of every entity that has not been removed.
+    fn live(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.position.len()).filter(|&i| self.alive[i])
+    }
+
+    fn id(&self, i: usize) -> EntityId {
+        EntityId { index: i, generation: self.generation[i] }
+    }
+
+    /// The slot index behind a handle, if the handle still refers to a live entity.
+    fn resolve(&self, id: EntityId) -> Result<usize, EntityError> {
+        match self.generation.get(id.index) {
+            None => Err(EntityError::Unknown(id)),
+            Some(&generation) if generation != id.generation || !self.alive[id.index] =>
+                Err(EntityError::Stale(id)),
+            Some(_) => Ok(id.index),
+        }
+    }
+
+    /// Cells just outside entity `i`'s footprint on the given side.
+    fn edge(&self, i: usize, side: Direction) -> Vec<Position> {
+        let (x, y) = self.position[i];
+        let (width, height) = (self.size[i].0 as isize, self.size[i].1 as isize);
+        match side {
+            Direction::North => (x..x + width).map(|cx| (cx, y - 1)).collect(),
+            Direction::South => (x..x + width).map(|cx| (cx, y + height)).collect(),
+            Direction::West => (y..y + height).map(|cy| (x - 1, cy)).collect(),
+            Direction::East => (y..y + height).map(|cy| (x + width, cy)).collect(),
+        }
+    }
+
+    /// Distinct entities touching entity `i`'s perimeter.
+    fn neighbors(&self, i: usize) -> Vec<usize> {
+        let mut neighbors = Vec::new();
+        for side in Direction::ALL {
+            for cell in self.edge(i, side) {
+                if let Some(j) = self.cells.get(cell) {
+                    if !neighbors.contains(&j) {
+                        neighbors.push(j);
+                    }
+                }
+            }
+        }
+        neighbors
+    }
+
+    /// Whether some cell along entity `i`'s `side` edge belongs to entity `j`.
+    fn touches(&self, i: usize, side: Direction, j: usize) -> bool {
+        self.edge(i, side).into_iter().any(|cell| self.cells.get(cell) == Some(j))
+    }
+
+    /// Whether `i` outputs into `j`: `j` is on `i`'s front edge and `i` on `j`'s back edge.
+    fn feeds(&self, i: usize, j: usize) -> bool {
+        self.touches(i, self.facing[i], j) && self.touches(j, self.facing[j].opposite(), i)
+    }
+
+    /// Links `i` to its neighbors along its whole perimeter: it feeds every entity its front
+    /// edge touches from behind, and any neighbor behind it that faces it feeds it. Pairs that
+    /// were disconnected or are already linked are left alone.
+    fn connect_adjacent(&mut self, i: usize) {
+        for j in self.neighbors(i) {
+            for (from, to) in [(i, j), (j, i)] {
+                let linked = self.downstream[from].contains(&to);
+                if !linked && !self.severed.contains(&(from, to)) && self.feeds(from, to) {
+                    self.link(from, to);
+                    self.adjacent.insert((from, to));
+                }
+            }
+        }
+    }
+
+    /// Turns an entity a quarter clockwise and, under `ConnectionPolicy::Adjacent`, rewires
+    /// the links adjacency made for the new facing. Links made with `connect` are kept, as
+    /// are pairs cut with `disconnect`. Adjacent links that survive the turn keep their rate
+    /// and latency, though anything on them arrives at once.
+    fn rotate(&mut self, id: EntityId) -> Result<Direction, EntityError> {
+        let i = self.resolve(id)?;
+        self.facing[i] = self.facing[i].clockwise();
+        if self.policy == ConnectionPolicy::Manual {
+            return Ok(self.facing[i]);
+        }
+        let mut settings = Vec::new();
+        for j in self.neighbors(i) {
+            for pair in [(i, j), (j, i)] {
+                if !self.adjacent.contains(&pair) {
+                    continue;
                 }
-            if self.position[i] == (x, y + 1) || // down
-                self.position[i] == (x + 1, y) {  // right
-                    downstream.push(i);
-                    self.upstream[i].push(len);
+                if let Some(link) = self.links.get(&pair) {
+                    settings.push((pair, link.rate, link.latency));
                 }
+                self.unlink(pair.0, pair.1);
+            }
+        }
+        self.connect_adjacent(i);
+        for ((from, to), rate, latency) in settings {
+            if self.downstream[from].contains(&to) {
+                self.links.insert((from, to), Link::new(rate, latency));
+            }
+        }
+        Ok(self.facing[i])
+    }
+
+    fn link(&mut self, from: usize, to: usize) {
+        if !self.downstream[from].contains(&to) {
+            self.downstream[from].push(to);
+            self.upstream[to].push(from);
+        }
+    }
+
+    /// Makes `from` feed `to`. Connecting an already connected pair only makes the link
+    /// explicit, so that rotating either entity keeps it.
+    fn connect(&mut self, from: EntityId, to: EntityId) -> Result<(), EntityError> {
+        let (from, to) = (self.resolve(from)?, self.resolve(to)?);
+        if from == to {
+            return Err(EntityError::SelfLink(self.id(from)));
+        }
+        self.link(from, to);
+        self.adjacent.remove(&(from, to));
+        self.severed.remove(&(from, to));
+        Ok(())
+    }
+
+    /// Drops the link from `from` to `to`. Anything still travelling along it arrives at once.
+    fn unlink(&mut self, from: usize, to: usize) {
+        self.downstream[from].retain(|&d| d != to);
+        self.upstream[to].retain(|&u| u != from);
+        self.adjacent.remove(&(from, to));
+        self.drop_link(from, to);
+    }
+
+    /// Forgets the settings of the link from `from` to `to`, delivering whatever is still on it.
+    fn drop_link(&mut self, from: usize, to: usize) {
+        let Some(mut link) = self.links.remove(&(from, to)) else { return };
+        for r in link.drain() {
+            self.in_transit[to].entry(r.id).quantity -= r.quantity;
+            self.has[to].add(r);
+        }
+    }
+
+    /// Limits how fast items enter the link from `from` to `to` and how many ticks they take
+    /// to arrive. The entities must already be connected; items already on the link arrive
+    /// at once, and the link keeps its place in both entities' link order.
+    fn set_link(&mut self, from: EntityId, to: EntityId, rate: Option<Quantity>, latency: u32) -> Result<(), EntityError> {
+        let (f, t) = (self.resolve(from)?, self.resolve(to)?);
+        if !self.downstream[f].contains(&t) {
+            return Err(EntityError::NotLinked(from, to));
+        }
+        self.drop_link(f, t);
+        self.links.insert((f, t), Link::new(rate, latency));
+        Ok(())
+    }
+
+    /// Units that may still enter the link from `from` to `to` this tick.
+    fn link_allowance(&self, from: usize, to: usize) -> Quantity {
+        self.links.get(&(from, to)).map_or(Quantity::MAX, |link| link.allowance(self.tick))
+    }
+
+    /// Removes the link from `from` to `to`, which must exist.
+    fn disconnect(&mut self, from: EntityId, to: EntityId) -> Result<(), EntityError> {
+        let (f, t) = (self.resolve(from)?, self.resolve(to)?);
+        if !self.downstream[f].contains(&t) {
+            return Err(EntityError::NotLinked(from, to));
+        }
+        self.unlink(f, t);
+        self.severed.insert((f, t));
+        Ok(())
+    }
+
+    fn set_capacity(&mut self, id: EntityId, capacity: Quantity) -> Result<(), EntityError> {
+        let i = self.resolve(id)?;
+        self.capacity[i] = capacity;
+        Ok(())
+    }
+
+    fn set_power(&mut self, id: EntityId, role: PowerRole) -> Result<(), EntityError> {
+        let i = self.resolve(id)?;
+        self.power[i] = role;
+        Ok(())
+    }
+
+    /// Power speed multiplier for entity `i` from the last solved grid, or zero while it is
+    /// broken down.
+    fn speed(&self, i: usize) -> f32 {
+        if self.broken[i] > 0 {
+            return 0.0;
         }
-        self.wants.push(wants);
-        self.has.push(has);
-        self.position.push(position);
-        self.visible.push(visible);
-        self.upstream.push(upstream);
-        self.downstream.push(downstream);
+        self.grid.speed.get(i).copied().unwrap_or(1.0)
+    }
+
+    fn set_breakdown(&mut self, id: EntityId, breakdown: Option<Breakdown>) -> Result<(), EntityError> {
+        let i = self.resolve(id)?;
+        self.breakdown[i] = breakdown;
+        Ok(())
+    }
+
+    /// Makes a sink consume a random amount between `low` and `high` units each tick.
+    fn set_demand(&mut self, id: EntityId, low: Quantity, high: Quantity) -> Result<(), EntityError> {
+        let i = self.resolve(id)?;
+        self.demand[i] = Some((low, high));
+        Ok(())
+    }
+
+    fn set_distribution(&mut self, id: EntityId, split: Distribution, merge: Distribution) -> Result<(), EntityError> {
+        let i = self.resolve(id)?;
+        self.split[i] = split;
+        self.merge[i] = merge;
+        Ok(())
     }
 
-    fn display(&self) -> Vec<(Position, String)> {
+    fn set_filter(&mut self, id: EntityId, filter: ResourceFilter) -> Result<(), EntityError> {
+        let i = self.resolve(id)?;
+        self.accepts[i] = filter;
+        Ok(())
+    }
+
+    /// Units entity `i` can still take before it is full.
+    fn room(&self, i: usize) -> Quantity {
+        self.capacity[i].saturating_sub(self.held(i))
+    }
+
+    /// Units entity `i` holds or has on the way to it.
+    fn held(&self, i: usize) -> Quantity {
+        self.has[i].total() + self.in_transit[i].total()
+    }
+
+    fn display(&self, registry: &Registry) -> Vec<(Position, String)> {
         let len = self.position.len();
         let mut output = Vec::with_capacity(len);
+        let alerts: Vec<usize> = self.events.last().iter()
+            .filter(|event| event.is_alert())
+            .map(|event| event.entity().index)
+            .collect();
         for i in 0..len {
-            if self.visible[i] {
-                //let repr = if self.has[i] < 128 { '*' } else { '!' };
-                let c: char = (48 + i as u8) as char;
-                let repr = match self.has[i] {
-                    Resource::A(x) if 0 <= x && x < 64 => format!("{ESC}[0;31;40m{c}"),
-                    Resource::A(x) if 64 <= x && x < 128 => format!("{ESC}[0;33;40m{c}"),
-                    Resource::A(x) if 128 <= x && x < 192 => format!("{ESC}[0;32;40m{c}"),
-                    Resource::A(_) => format!("{ESC}[0;34;40m{c}"),
-                    Resource::B(x) if 0 <= x && x < 64 => format!("{ESC}[0;31;40m{c}"),
-                    Resource::B(x) if 64 <= x && x < 128 => format!("{ESC}[0;33;40m{c}"),
-                    Resource::B(x) if 128 <= x && x < 192 => format!("{ESC}[0;32;40m{c}"),
-                    Resource::B(_) => format!("{ESC}[0;34;40m{c}"),
+            if self.alive[i] && self.visible[i] {
+                let c = match (self.has[i].largest(), self.power[i]) {
+                    (Some(r), _) => registry.get(r.id).glyph,
+                    (None, PowerRole::Pole { .. }) => '+',
+                    (None, PowerRole::Generator { .. }) => '#',
+                    (None, _) if matches!(self.kind[i], EntityKind::Conveyor { .. }) => self.facing[i].arrow(),
+                    (None, _) => '.',
                 };
-                output.push((self.position[i], repr));
+                let color = fill_color(self.has[i].total(), self.capacity[i]);
+                // Starved and blocked entities stand out on a red background.
+                let background = if alerts.contains(&i) { 41 } else { 40 };
+                let repr = format!("{ESC}[0;{color};{background}m{c}");
+                for cell in footprint(self.position[i], self.size[i]) {
+                    output.push((cell, repr.clone()));
+                }
             }
         }
         output
     }
 
     fn debug_entity(&self, i: usize) {
-        println!("Index: {}\tHas: {:?}\tWants:{:?}\tPosition: {:?}\tVisible: {:?}\tUpstream: {:?}\tDownstream: {:?}",
-                 i,
+        println!("Entity: {}\tKind: {}\tCrafting: {:?}\tPower: {:?} @ {:.2}\tBroken: {}\tDelivered: {:?}\tHas: {:?}\tIn transit: {:?}\tCapacity: {}\tAccepts: {:?}\tWants:{:?}\tPosition: {:?} size {:?} facing {:?}\tVisible: {:?}\tUpstream: {:?}\tDownstream: {:?}",
+                 self.id(i),
+                 self.kind[i],
+                 self.crafting[i],
+                 self.power[i],
+                 self.speed(i),
+                 self.broken[i],
+                 self.delivered[i],
                  self.has[i],
+                 self.in_transit[i],
+                 self.capacity[i],
+                 self.accepts[i],
                  self.wants[i],
                  self.position[i],
+                 self.size[i],
+                 self.facing[i],
                  self.visible[i],
                  self.upstream[i],
                  self.downstream[i]);
     }
 
-    fn update(&mut self) {
-        let len = self.position.len();
-        for i in 0..len {
-            for u in &self.upstream[i] {
-                if self.has[i] != Resource::A(u8::MAX) {
-                    if self.has[*u] >= self.wants[i] {
-                        self.has[i] += self.wants[i];
-                        self.has[*u] -= self.wants[i];
-                    } else {
-                        let remainder = self.has[*u];
-                        self.has[i] += remainder;
-                        self.has[*u] = Resource::A(0);
+    fn update(&mut self, registry: &Registry, rng: &mut Rng) -> Vec<TransferError> {
+        let mut errors = Vec::new();
+        self.ledger = Ledger::default();
+        self.tick += 1;
+        let stock_before = if self.audit { self.stock() } else { Inventory::new() };
+        self.grid = PowerGrid::solve(&self.power, &self.position);
+        self.break_down(rng);
+        self.deliver();
+        self.produce(registry);
+        self.received.fill(0);
+        match self.mode {
+            UpdateMode::Sequential => self.transfer_sequential(registry, &mut errors),
+            UpdateMode::Buffered => self.transfer_buffered(registry, &mut errors),
+        }
+        self.report_transfers(registry);
+        self.craft(registry, rng);
+        self.consume(rng);
+        self.events.flush(self.tick);
+        if self.audit {
+            if let Err(imbalance) = self.ledger.reconcile(&stock_before, &self.stock()) {
+                panic!("Conservation violated: {imbalance}\n{}", self.ledger);
+            }
+        }
+        errors
+    }
+
+    /// What entity `i` tries to pull this tick. Conveyors take up to `room` of any kind their
+    /// suppliers hold.
+    fn pull_list(&self, i: usize, upstream: &[usize], state: &[Inventory], room: Option<Quantity>) -> Vec<Resource> {
+        match room {
+            Some(room) => {
+                let mut kinds = Inventory::new();
+                for &u in upstream {
+                    for r in state[u].iter().filter(|r| r.quantity > 0) {
+                        kinds.entry(r.id);
+                    }
+                }
+                kinds.iter().map(|r| Resource::new(r.id, room)).collect()
+            }
+            None => self.wants[i].iter().copied().collect(),
+        }
+    }
+
+    /// Upstream entities of `i` that offer `id`, with how much of it each can send: what it
+    /// holds in `state`, capped by the link's rate.
+    fn suppliers(&self, i: usize, upstream: &[usize], state: &[Inventory], id: ResourceId) -> Vec<(usize, Quantity)> {
+        upstream.iter()
+            .filter(|&&u| self.kind[u].offers(id))
+            .map(|&u| (u, state[u].get(id).min(self.link_allowance(u, i))))
+            .filter(|&(_, available)| available > 0)
+            .collect()
+    }
+
+    /// Pulls into each entity in slot order, so items that arrive early in the pass can move on
+    /// again before the pass ends. Each entity's merge policy decides which suppliers it draws
+    /// from; suppliers serve whoever pulls first, so split policies only apply when buffered.
+    fn transfer_sequential(&mut self, registry: &Registry, errors: &mut Vec<TransferError>) {
+        // Suppliers feeding several receivers set aside a share for each by their split
+        // policy the first time one of them pulls, so slot order does not decide who is served.
+        let mut allotments: HashMap<(usize, ResourceId), Vec<(usize, Quantity)>> = HashMap::new();
+        for i in 0..self.position.len() {
+            if !self.alive[i] {
+                continue;
+            }
+            // Conveyors carry anything, but only up to their rate per tick.
+            let mut room = match self.kind[i] {
+                EntityKind::Conveyor { rate } => Some(rate.saturating_sub(self.has[i].total())),
+                _ => None,
+            };
+            let upstream: Vec<usize> = self.upstream[i].iter().copied().filter(|&u| u != i).collect();
+            for want in self.pull_list(i, &upstream, &self.has, room) {
+                if !self.accepts[i].allows(want.id) {
+                    continue;
+                }
+                let suppliers = self.suppliers(i, &upstream, &self.has, want.id);
+                let quantity = room.map_or(want.quantity, |room| room.min(want.quantity));
+                for (u, share) in distribute(self.merge[i], &suppliers, quantity, self.tick) {
+                    let mut allotted = None;
+                    if self.downstream[u].len() > 1 {
+                        let shares = allotments.entry((u, want.id)).or_insert_with(|| self.allot(registry, u, want.id));
+                        allotted = shares.iter_mut().find(|(d, _)| *d == i).map(|(_, allotted)| allotted);
                     }
+                    let share = allotted.as_deref().map_or(share, |&allotted| share.min(allotted));
+                    let free = self.capacity[i].saturating_sub(self.held(i) + self.kind[i].reserved());
+                    let held = self.has[i].get(want.id) + self.in_transit[i].get(want.id);
+                    let limit = self.kind[i].limit(registry, want.id).min(held + free);
+                    let moved = self.apply_transfer(u, i, want.id, share, limit, errors);
+                    if let Some(allotted) = allotted {
+                        *allotted -= moved;
+                    }
+                    if let Some(room) = &mut room {
+                        *room = room.saturating_sub(moved);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reports receivers that got nothing they wanted as starved, and those that were too full
+    /// to take what their suppliers still hold as blocked. Assemblers report starving when
+    /// they fail to start a craft instead, and are not blocked while crafting or holding only
+    /// ingredients, since full input buffers are how they normally run.
+    fn report_transfers(&mut self, registry: &Registry) {
+        for i in 0..self.position.len() {
+            if !self.alive[i] {
+                continue;
+            }
+            let wanted: Vec<ResourceId> = match self.kind[i] {
+                EntityKind::Conveyor { .. } => self.upstream[i].iter()
+                    .flat_map(|&u| self.has[u].iter().filter(|r| r.quantity > 0).map(|r| r.id))
+                    .collect(),
+                _ => self.wants[i].iter().filter(|w| w.quantity > 0).map(|w| w.id).collect(),
+            };
+            let busy = self.crafting[i].is_some() || match &self.kind[i] {
+                EntityKind::Assembler(recipe) => self.has[i].iter()
+                    .all(|r| r.quantity == 0 || recipe.inputs.get(r.id) > 0),
+                _ => false,
+            };
+            let blocked = !busy && wanted.iter().any(|&id| {
+                self.appetite(registry, i, id) == 0 && self.upstream[i].iter()
+                    .any(|&u| u != i && self.kind[u].offers(id) && self.has[u].get(id) > 0)
+            });
+            let starved = self.received[i] == 0
+                && !matches!(self.kind[i], EntityKind::Assembler(_))
+                && match self.kind[i] {
+                    EntityKind::Conveyor { rate } => self.has[i].total() < rate && self.room(i) > 0,
+                    _ => self.wants[i].iter().any(|w| self.appetite(registry, i, w.id) > 0),
+                };
+            if blocked {
+                self.events.emit(Event::Blocked { entity: self.id(i) });
+            } else if starved {
+                self.events.emit(Event::Starved { entity: self.id(i) });
+            }
+        }
+    }
+
+    /// Divides what supplier `u` holds of `id` among its receivers by its split policy, each
+    /// asking for as much as it could take right now.
+    fn allot(&self, registry: &Registry, u: usize, id: ResourceId) -> Vec<(usize, Quantity)> {
+        let mut receivers: Vec<usize> = self.downstream[u].iter().copied().filter(|&d| d != u).collect();
+        if self.split[u] != Distribution::Priority {
+            receivers.sort_by_key(|&d| (self.position[d].1, self.position[d].0));
+        }
+        let asked: Vec<(usize, Quantity)> = receivers.iter()
+            .map(|&d| (d, self.appetite(registry, d, id).min(self.link_allowance(u, d))))
+            .collect();
+        distribute(self.split[u], &asked, self.has[u].get(id), self.tick)
+    }
+
+    /// How much of `id` entity `d` could take in this tick.
+    fn appetite(&self, registry: &Registry, d: usize, id: ResourceId) -> Quantity {
+        if !self.accepts[d].allows(id) {
+            return 0;
+        }
+        let free = self.capacity[d].saturating_sub(self.held(d) + self.kind[d].reserved());
+        let held = self.has[d].get(id) + self.in_transit[d].get(id);
+        let stack_room = self.kind[d].limit(registry, id).saturating_sub(held);
+        let wanted = match self.kind[d] {
+            EntityKind::Conveyor { rate } => rate.saturating_sub(self.has[d].total()),
+            _ => self.wants[d].get(id),
+        };
+        wanted.min(free).min(stack_room)
+    }
+
+    /// Plans every pull against the state at the start of the pass, then carries them all out.
+    /// Nothing moves more than one hop per tick. Receivers spread their pulls by their merge
+    /// policy and short suppliers share out by their split policy; ties go by map position
+    /// rather than slot order unless a policy says otherwise. Entities plan after everything
+    /// downstream of them, so what they pass on this tick frees room for what they take in.
+    fn transfer_buffered(&mut self, registry: &Registry, errors: &mut Vec<TransferError>) {
+        let snapshot = &self.has;
+        let by_position = |&a: &usize, &b: &usize| {
+            let ((ax, ay), (bx, by)) = (self.position[a], self.position[b]);
+            (ay, ax).cmp(&(by, bx))
+        };
+
+        let order = self.downstream_first(by_position);
+        let mut requests: Vec<Transfer> = Vec::new();
+        let mut granted = vec![Inventory::new(); self.position.len()];
+        let mut grants = Vec::new();
+        for &i in &order {
+            self.grant(i, &mut requests, &mut granted, &mut grants, by_position);
+            let outflow = &granted[i];
+            let held = snapshot[i].total() + self.in_transit[i].total() + self.kind[i].reserved();
+            let mut free = self.capacity[i].saturating_sub(held.saturating_sub(outflow.total()));
+            let mut room = match self.kind[i] {
+                EntityKind::Conveyor { rate } => Some(rate.saturating_sub(snapshot[i].total() - outflow.total())),
+                _ => None,
+            };
+            let mut incoming = Inventory::new();
+            let mut upstream: Vec<usize> = self.upstream[i].iter().copied().filter(|&u| u != i).collect();
+            if self.merge[i] != Distribution::Priority {
+                upstream.sort_by(by_position);
+            }
+            for want in self.pull_list(i, &upstream, snapshot, room) {
+                if !self.accepts[i].allows(want.id) {
+                    continue;
+                }
+                let limit = self.kind[i].limit(registry, want.id);
+                let stack = snapshot[i].get(want.id) - outflow.get(want.id);
+                let stack_room = limit.saturating_sub(stack + self.in_transit[i].get(want.id) + incoming.get(want.id));
+                let quantity = want.quantity
+                    .min(free)
+                    .min(stack_room)
+                    .min(room.unwrap_or(Quantity::MAX));
+                let suppliers = self.suppliers(i, &upstream, snapshot, want.id);
+                for (u, share) in distribute(self.merge[i], &suppliers, quantity, self.tick) {
+                    if share == 0 {
+                        continue;
+                    }
+                    free -= share;
+                    if let Some(room) = &mut room {
+                        *room -= share;
+                    }
+                    incoming.add(Resource::new(want.id, share));
+                    requests.push(Transfer { from: u, to: i, resource: Resource::new(want.id, share) });
+                }
+            }
+        }
+        // Around a cycle some pulls reach suppliers that already planned; they get what is left.
+        for &i in &order {
+            self.grant(i, &mut requests, &mut granted, &mut grants, by_position);
+        }
+
+        for grant in grants {
+            self.apply_transfer(grant.from, grant.to, grant.resource.id, grant.resource.quantity, Quantity::MAX, errors);
+        }
+    }
+
+    /// Live entities ordered so that each comes after everything it feeds, except where links
+    /// form a cycle. Ties go by map position so the order does not depend on slot numbers.
+    fn downstream_first(&self, by_position: impl Fn(&usize, &usize) -> std::cmp::Ordering) -> Vec<usize> {
+        let mut roots: Vec<usize> = self.live().collect();
+        roots.sort_by(&by_position);
+        let sorted_downstream = |i: usize| {
+            let mut downstream = self.downstream[i].clone();
+            downstream.sort_by(&by_position);
+            downstream
+        };
+        let mut visited = vec![false; self.position.len()];
+        let mut order = Vec::with_capacity(roots.len());
+        for root in roots {
+            if visited[root] {
+                continue;
+            }
+            visited[root] = true;
+            let mut stack = vec![(root, sorted_downstream(root), 0)];
+            while let Some((i, downstream, next)) = stack.last_mut() {
+                match downstream.get(*next) {
+                    Some(&d) => {
+                        *next += 1;
+                        if !visited[d] {
+                            visited[d] = true;
+                            stack.push((d, sorted_downstream(d), 0));
+                        }
+                    }
+                    None => {
+                        order.push(*i);
+                        stack.pop();
+                    }
+                }
+            }
+        }
+        order
+    }
+
+    /// Shares out what `from` holds among the pulls made on it so far, by its split policy,
+    /// adding the results to `grants` and to `granted[from]`.
+    fn grant(&self, from: usize, requests: &mut Vec<Transfer>, granted: &mut [Inventory], grants: &mut Vec<Transfer>,
+             by_position: impl Fn(&usize, &usize) -> std::cmp::Ordering) {
+        let (mut group, rest): (Vec<Transfer>, Vec<Transfer>) = requests.drain(..).partition(|t| t.from == from);
+        *requests = rest;
+        group.sort_by_key(|t| t.resource.id);
+        for group in group.chunk_by_mut(|a, b| a.resource.id == b.resource.id) {
+            let id = group[0].resource.id;
+            match self.split[from] {
+                Distribution::Priority => {
+                    let rank = |to: usize| self.downstream[from].iter().position(|&d| d == to);
+                    group.sort_by_key(|t| rank(t.to));
+                }
+                _ => group.sort_by(|a, b| by_position(&a.to, &b.to)),
+            }
+            let asked: Vec<(usize, Quantity)> = group.iter().map(|t| (t.to, t.resource.quantity)).collect();
+            let stock = self.has[from].get(id) - granted[from].get(id);
+            for (to, share) in distribute(self.split[from], &asked, stock, self.tick) {
+                granted[from].add(Resource::new(id, share));
+                grants.push(Transfer { from, to, resource: Resource::new(id, share) });
+            }
+        }
+    }
+
+    /// Moves up to `quantity` of `id` from one entity to another without taking what the
+    /// receiver holds and has on the way past `limit`, recording the result in the ledger. Over a link with latency the items
+    /// leave at once but only arrive in a later `deliver`. Returns the units moved.
+    fn apply_transfer(&mut self, from: usize, to: usize, id: ResourceId, quantity: Quantity, limit: Quantity,
+                      errors: &mut Vec<TransferError>) -> Quantity {
+        let quantity = quantity.min(self.link_allowance(from, to));
+        let latency = self.links.get(&(from, to)).map_or(0, |link| link.latency);
+        let (source_has, dest_has) = pair_mut(&mut self.has, from, to);
+        let in_transit = &mut self.in_transit[to];
+        let pair_before = source_has.total() + dest_has.total() + in_transit.total();
+        let Some(source) = source_has.get_mut(id) else { return 0 };
+        let quantity = quantity.min(limit.saturating_sub(dest_has.get(id) + in_transit.get(id)));
+        let result = if latency == 0 {
+            source.try_transfer(dest_has.entry(id), quantity, Quantity::MAX)
+        } else {
+            source.try_transfer(in_transit.entry(id), quantity, Quantity::MAX)
+        };
+        let moved = match result {
+            Ok(moved) => {
+                self.ledger.transferred.add(Resource::new(id, moved));
+                self.received[to] += moved;
+                if let Some(link) = self.links.get_mut(&(from, to)) {
+                    link.send(Resource::new(id, moved), self.tick);
+                }
+                moved
+            }
+            Err(error) => {
+                errors.push(TransferError { from, to, error });
+                0
+            }
+        };
+        if self.audit {
+            assert_eq!(source_has.total() + dest_has.total() + in_transit.total(), pair_before,
+                       "transfer {from} -> {to} of resource #{} did not conserve units", id.0);
+        }
+        moved
+    }
+
+    /// Hands over everything that has finished travelling along a link.
+    fn deliver(&mut self) {
+        for (&(_, to), link) in self.links.iter_mut() {
+            for r in link.arrivals(self.tick) {
+                self.in_transit[to].entry(r.id).quantity -= r.quantity;
+                self.has[to].add(r);
+            }
+        }
+    }
+
+    /// Everything held by or on the way to every entity, summed per resource kind.
+    fn stock(&self) -> Inventory {
+        let mut stock = Inventory::new();
+        for has in self.has.iter().chain(&self.in_transit) {
+            for r in has.iter() {
+                stock.add(*r);
+            }
+        }
+        stock
+    }
+
+    /// Sources add their output, up to what they can hold. Underpowered sources build up
+    /// partial work and produce once a whole tick's worth has accumulated.
+    fn produce(&mut self, registry: &Registry) {
+        for i in 0..self.position.len() {
+            if !self.alive[i] {
+                continue;
+            }
+            if let EntityKind::Source { produces } = self.kind[i] {
+                self.work[i] += self.speed(i);
+                if self.work[i] < 1.0 {
+                    continue;
+                }
+                self.work[i] -= 1.0;
+                let room = self.room(i);
+                let limit = self.kind[i].limit(registry, produces.id);
+                let stack = self.has[i].entry(produces.id);
+                // A source seeded past its limit keeps its stock but makes nothing more.
+                let limit = limit.min(stack.quantity.saturating_add(room));
+                let made = Resource::new(produces.id, produces.quantity.min(limit.saturating_sub(stack.quantity)));
+                stack.quantity += made.quantity;
+                self.ledger.produced.add(made);
+                let entity = self.id(i);
+                if made.quantity > 0 {
+                    self.events.emit(Event::Produced { entity, resource: made });
+                }
+                if made.quantity < produces.quantity {
+                    self.events.emit(Event::Blocked { entity });
+                }
+            }
+        }
+    }
+
+    /// Sinks empty themselves into their delivered tally.
+    fn consume(&mut self, rng: &mut Rng) {
+        for i in 0..self.position.len() {
+            if !self.alive[i] {
+                continue;
+            }
+            if let EntityKind::Sink = self.kind[i] {
+                let eaten = match self.demand[i] {
+                    None => std::mem::take(&mut self.has[i]),
+                    Some((low, high)) => {
+                        let mut budget = rng.range(low, high);
+                        let mut eaten = Inventory::new();
+                        for r in self.has[i].iter() {
+                            let take = r.quantity.min(budget);
+                            budget -= take;
+                            eaten.add(Resource::new(r.id, take));
+                        }
+                        self.has[i].remove(&eaten);
+                        eaten
+                    }
+                };
+                let entity = self.id(i);
+                for r in eaten.iter().filter(|r| r.quantity > 0) {
+                    self.delivered[i].add(*r);
+                    self.ledger.consumed.add(*r);
+                    self.events.emit(Event::Consumed { entity, resource: *r });
+                }
+            }
+        }
+    }
+
+    /// Running entities that can fail may break down; broken ones count down their repair.
+    /// Idle and unpowered entities never fail.
+    fn break_down(&mut self, rng: &mut Rng) {
+        for i in 0..self.position.len() {
+            if !self.alive[i] {
+                continue;
+            }
+            let Some(breakdown) = self.breakdown[i] else { continue };
+            let running = self.speed(i) > 0.0
+                && !matches!(self.kind[i], EntityKind::Assembler(_) if self.crafting[i].is_none());
+            if self.broken[i] > 0 {
+                self.broken[i] -= 1;
+            } else if running && rng.chance(breakdown.chance) {
+                self.broken[i] = breakdown.repair;
+            }
+        }
+    }
+
+    /// Advances every assembler by its power speed: finishes crafts whose time is up (if the
+    /// outputs fit) and starts a new craft when the inputs are on hand.
+    fn craft(&mut self, registry: &Registry, rng: &mut Rng) {
+        for i in 0..self.position.len() {
+            if !self.alive[i] {
+                continue;
+            }
+            let EntityKind::Assembler(recipe) = &self.kind[i] else { continue };
+            let entity = self.id(i);
+            let speed = self.speed(i);
+            if let Some(remaining) = self.crafting[i] {
+                let remaining = (remaining - speed).max(0.0);
+                let fits = recipe.outputs.total() <= self.room(i) && recipe.outputs.iter()
+                    .all(|r| self.has[i].get(r.id) + r.quantity <= registry.get(r.id).stack_size);
+                if remaining == 0.0 && fits {
+                    for output in recipe.outputs.iter() {
+                        let lost = self.has[i].add(*output);
+                        self.ledger.produced.add(*output);
+                        self.ledger.lost.add(Resource::new(output.id, lost));
+                        self.events.emit(Event::Produced { entity, resource: *output });
+                    }
+                    self.crafting[i] = None;
+                } else {
+                    if remaining == 0.0 {
+                        self.events.emit(Event::Blocked { entity });
+                    }
+                    self.crafting[i] = Some(remaining);
                 }
             }
+            if self.crafting[i].is_none() && speed > 0.0 {
+                if !self.has[i].contains(&recipe.inputs) {
+                    self.events.emit(Event::Starved { entity });
+                    continue;
+                }
+                self.has[i].remove(&recipe.inputs);
+                for input in recipe.inputs.iter() {
+                    self.ledger.consumed.add(*input);
+                    self.events.emit(Event::Consumed { entity, resource: *input });
+                }
+                let low = recipe.duration.saturating_sub(recipe.jitter).max(1);
+                self.crafting[i] = Some(rng.range(low, recipe.duration + recipe.jitter) as f32);
+            }
         }
     }
 }
 
 struct World {
     entities: Entities,
+    registry: Registry,
     size: (usize, usize),
+    /// Simulation rate at 1× speed.
     ticks_per_second: u32,
+    /// Source of every random decision in the simulation.
+    rng: Rng,
+    /// Multiplier on `ticks_per_second`, between `MIN_SPEED` and `MAX_SPEED`.
+    speed: f64,
     tick_time: time::Duration,
+    /// While set, ticks only run when requested with `advance`.
+    paused: bool,
+    /// Ticks requested with `advance` that have not run yet.
+    pending_steps: u32,
+    /// Target time between rendered frames, independent of the simulation rate.
+    frame_time: time::Duration,
     ticks: usize,
+    /// Frames that could not be drawn on time because the previous one ran long.
+    dropped_frames: usize,
+    /// Ticks abandoned because the loop fell more than `MAX_CATCH_UP_TICKS` behind.
+    skipped_ticks: usize,
 }
 
 impl World {
-    fn new() -> Self {
+    fn new(registry: Registry, seed: u64) -> Self {
+        let size = (64, 32);
         Self {
-            entities: Entities::new(),
-            size: (64, 32),
+            entities: Entities::new(size),
+            registry,
+            size,
             ticks_per_second: 4,
+            rng: Rng::new(seed),
+            speed: 1.0,
             tick_time: time::Duration::from_millis(1000 / 4),
+            paused: false,
+            pending_steps: 0,
+            frame_time: time::Duration::from_millis(1000 / 10),
             ticks: 1,
+            dropped_frames: 0,
+            skipped_ticks: 0,
         }
     }
 
@@ -193,7 +1428,7 @@ impl World {
     }
 
     fn display(&self) {
-        let output = self.entities.display();
+        let output = self.entities.display(&self.registry);
         self.display_clear();
         self.display_border_top();
         self.display_border_sides();
@@ -205,43 +1440,586 @@ impl World {
         print!("{ESC}[0;0m{ESC}[0;37;40m");
 
         self.display_border_bottom();
-        println!("");
+        println!();
+        self.display_legend();
     }
 
-    fn update(&mut self) {
-        for i in 0..self.entities.position.len() {
-            self.entities.debug_entity(i);
+    fn display_legend(&self) {
+        for (_, def) in self.registry.iter() {
+            print!("{ESC}[0;{};40m{}{ESC}[0;37;40m {}  ", def.color, def.glyph, def.name);
         }
-        self.entities.update();
+        println!("{ESC}[0;0m");
     }
 
-    fn tick(&mut self) {
-        let tick_duration = time::Instant::now();
+    /// Draws the world followed by the debug lines for every live entity, the transfer
+    /// errors since the last frame, the ledger and the power networks.
+    fn render(&self, errors: &[TransferError]) {
         self.display();
-        self.update();
-        let sleep_time = self.tick_time - tick_duration.elapsed();
-        println!("Render time: {:?}\nFrame time: {:?}\nTarget frame time: {:?}\tTick #: {}",
-                 tick_duration.elapsed(),
-                 sleep_time + tick_duration.elapsed(),
-                 self.tick_time,
-                 self.ticks);
-        thread::sleep(sleep_time);
+        for i in 0..self.entities.position.len() {
+            if self.entities.alive[i] {
+                self.entities.debug_entity(i);
+            }
+        }
+        for error in errors {
+            println!("{error}");
+        }
+        for event in self.entities.events.last().iter().filter(|event| event.is_alert()) {
+            println!("{event}");
+        }
+        println!("{}", self.entities.ledger);
+        for (n, network) in self.entities.grid.networks.iter().enumerate() {
+            println!("Power network {n}: {network}");
+        }
+    }
+
+    /// Sets the speed multiplier, clamped to `MIN_SPEED`..=`MAX_SPEED`, and recalculates
+    /// `tick_time` to match. Takes effect from the next tick; NaN and infinities are ignored.
+    fn set_speed(&mut self, speed: f64) {
+        if !speed.is_finite() {
+            return;
+        }
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        self.tick_time = time::Duration::from_secs_f64(1.0 / (self.ticks_per_second as f64 * self.speed));
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.pending_steps = 0;
+    }
+
+    /// Runs `ticks` more ticks while paused, one per frame.
+    fn advance(&mut self, ticks: u32) {
+        self.pending_steps += ticks;
+    }
+
+    fn apply(&mut self, control: Control) {
+        match control {
+            Control::TogglePause => self.set_paused(!self.paused),
+            Control::Step => self.advance(1),
+            Control::Faster => self.set_speed(self.speed * 2.0),
+            Control::Slower => self.set_speed(self.speed / 2.0),
+            Control::Speed(speed) => self.set_speed(speed),
+        }
+    }
+
+    /// Advances the simulation one tick without drawing or printing anything.
+    fn step(&mut self) -> Vec<TransferError> {
+        let errors = self.entities.update(&self.registry, &mut self.rng);
         self.ticks += 1;
+        errors
+    }
+
+    /// Advances the simulation `ticks` ticks as fast as possible with no terminal output,
+    /// collecting any transfer errors, and returns the resulting state.
+    fn run_headless(&mut self, ticks: usize, errors: &mut Vec<TransferError>) -> &Entities {
+        for _ in 0..ticks {
+            errors.extend(self.step());
+        }
+        &self.entities
+    }
+
+    /// Runs the simulation at a fixed `tick_time` step and renders every `frame_time`. Time
+    /// that passes while rendering accumulates as lag and is worked off with extra ticks
+    /// before the next frame, up to `MAX_CATCH_UP_TICKS`. Controls read from stdin are
+    /// applied at the start of each frame.
+    fn run(&mut self) -> ! {
+        let controls = control::spawn_reader();
+        let mut previous = time::Instant::now();
+        let mut lag = time::Duration::ZERO;
+        let mut errors = Vec::new();
+        loop {
+            let frame_start = time::Instant::now();
+            for control in controls.try_iter() {
+                self.apply(control);
+            }
+            lag += frame_start - previous;
+            previous = frame_start;
+            let mut steps = 0;
+            if self.paused {
+                lag = time::Duration::ZERO;
+                if self.pending_steps > 0 {
+                    self.pending_steps -= 1;
+                    errors.extend(self.step());
+                    steps += 1;
+                }
+            }
+            let budget = (self.frame_time.as_nanos() / self.tick_time.as_nanos()) as u32 + MAX_CATCH_UP_TICKS;
+            while lag >= self.tick_time {
+                if steps == budget {
+                    let behind = lag.as_nanos() / self.tick_time.as_nanos();
+                    self.skipped_ticks += behind as usize;
+                    lag -= self.tick_time * behind as u32;
+                    break;
+                }
+                errors.extend(self.step());
+                lag -= self.tick_time;
+                steps += 1;
+            }
+            self.render(&errors);
+            errors.clear();
+            let frame_duration = frame_start.elapsed();
+            if frame_duration > self.frame_time {
+                self.dropped_frames += (frame_duration.as_nanos() / self.frame_time.as_nanos()) as usize;
+            }
+            println!("Frame time: {:?} (target {:?})\tTicks this frame: {steps}\tDropped frames: {}\tSkipped ticks: {}",
+                     frame_duration,
+                     self.frame_time,
+                     self.dropped_frames,
+                     self.skipped_ticks);
+            println!("Target tick time: {:?} ({} ticks/s × {}{})\tTick #: {}",
+                     self.tick_time,
+                     self.ticks_per_second,
+                     self.speed,
+                     if self.paused { ", paused" } else { "" },
+                     self.ticks);
+            println!("Controls (type + Enter): p pause/resume, s step, + faster, - slower, 0.25-64 set speed");
+            thread::sleep(self.frame_time.saturating_sub(frame_start.elapsed()));
+        }
     }
 }
 
-fn setup_chain(world: &mut World) {
-    world.entities.insert(Resource::A(1), Resource::A(100), (1, 1), true);
-    world.entities.insert(Resource::A(1), Resource::A(255), (1, 2), true);
-    world.entities.insert(Resource::A(2), Resource::A(64), (2, 2), true);
-    world.entities.insert(Resource::A(2), Resource::A(192), (3, 2), true);
-    world.entities.insert(Resource::A(5), Resource::A(0), (3, 3), true);
+fn setup_chain(world: &mut World) -> Result<(), Box<dyn Error>> {
+    let r = &world.registry;
+    let ore = |n| r.resource("iron_ore", n);
+    let coal = |n| r.resource("coal", n);
+    let plate = |n| r.resource("iron_plate", n);
+    let smelt = Recipe::new("smelt iron", Inventory::from([ore(2), coal(1)]), Inventory::from([plate(1)]), 4).with_jitter(1);
+    let entities = &mut world.entities;
+    entities.place(EntityKind::Source { produces: ore(2) }, Inventory::new(), Inventory::new(), Placement::new((0, 2), Direction::East), true)?;
+    // Lay a slow belt, then upgrade it in place; the new belt reuses the old slot.
+    entities.place(EntityKind::Conveyor { rate: 1 }, Inventory::new(), Inventory::new(), Placement::new((1, 2), Direction::South), true)?;
+    let slow_belt = entities.at((1, 2)).expect("belt was just placed");
+    entities.remove(slow_belt)?;
+    // Placed facing away from the source, then turned toward the storage.
+    let belt = entities.place(EntityKind::Conveyor { rate: 2 }, Inventory::new(), Inventory::new(), Placement::new((1, 2), Direction::North), true)?;
+    entities.rotate(belt)?;
+    entities.set_filter(belt, ResourceFilter::Only(vec![ore(0).id]))?;
+    let storage = entities.place(EntityKind::Storage, Inventory::from([ore(2), coal(1)]), Inventory::from([coal(40)]), Placement::new((2, 2), Direction::East).with_size(1, 2), true)?;
+    let coal_source = entities.place(EntityKind::Source { produces: coal(1) }, Inventory::new(), Inventory::new(), Placement::new((1, 3), Direction::East), true)?;
+    let smelter = entities.place(EntityKind::Assembler(smelt.clone()), smelt.inputs, Inventory::new(), Placement::new((3, 2), Direction::East).with_size(2, 2), true)?;
+    entities.set_capacity(smelter, 24)?;
+    entities.set_power(smelter, PowerRole::Consumer { demand: 5 })?;
+    entities.set_breakdown(smelter, Some(Breakdown { chance: 0.02, repair: 6 }))?;
+    // A long belt: two ore per tick at most, taking three ticks to arrive.
+    entities.set_link(belt, storage, Some(2), 3)?;
+    // Route fresh coal straight to the smelter rather than through the storage.
+    entities.disconnect(coal_source, storage)?;
+    entities.connect(coal_source, smelter)?;
+    // Draw coal from the stockpile and the fresh supply in turns.
+    entities.set_distribution(smelter, Distribution::Proportional, Distribution::RoundRobin)?;
+    entities.policy = ConnectionPolicy::Manual;
+    // Below the storage, but fed by the smelter instead.
+    let sink = entities.place(EntityKind::Sink, Inventory::from([plate(1)]), Inventory::new(), Placement::new((2, 4), Direction::South), true)?;
+    entities.connect(smelter, sink)?;
+    entities.set_demand(sink, 0, 1)?;
+    let generator = entities.place(EntityKind::Structure, Inventory::new(), Inventory::new(), Placement::new((7, 3), Direction::South), true)?;
+    entities.set_power(generator, PowerRole::Generator { output: 4 })?;
+    let pole = entities.place(EntityKind::Structure, Inventory::new(), Inventory::new(), Placement::new((5, 4), Direction::South), true)?;
+    entities.set_power(pole, PowerRole::Pole { range: 2 })?;
+    entities.policy = ConnectionPolicy::Adjacent;
+    Ok(())
 }
 
 fn main() {
-    let mut world = World::new();
-    setup_chain(&mut world);
-    loop {
-        world.tick();
+    let registry = Registry::load(RESOURCE_DEFINITIONS).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        process::exit(1);
+    });
+    let seed = match flag_value("--seed") {
+        None => DEFAULT_SEED,
+        Some(seed) => seed.parse().unwrap_or_else(|_| {
+            eprintln!("--seed expects a number, got `{seed}`");
+            process::exit(1);
+        }),
+    };
+    let mut world = World::new(registry, seed);
+    if let Err(e) = setup_chain(&mut world) {
+        eprintln!("Scenario setup failed: {e}");
+        process::exit(1);
+    }
+    if std::env::args().any(|arg| arg == "--buffered") {
+        world.entities.mode = UpdateMode::Buffered;
+    }
+    if std::env::args().any(|arg| arg == "--analyze") {
+        println!("{}", Analysis::run(&world.entities));
+        return;
+    }
+    if let Some(ticks) = flag_value("--headless") {
+        let Ok(ticks) = ticks.parse() else {
+            eprintln!("--headless expects a tick count, got `{ticks}`");
+            process::exit(1);
+        };
+        let alerts = Rc::new(Cell::new(0));
+        let counter = Rc::clone(&alerts);
+        world.entities.events.subscribe(move |_, events| {
+            counter.set(counter.get() + events.iter().filter(|event| event.is_alert()).count());
+        });
+        let mut errors = Vec::new();
+        let stock = world.run_headless(ticks, &mut errors).stock();
+        for error in &errors {
+            println!("{error}");
+        }
+        println!("Ran {ticks} ticks, {} transfer errors, {} starved or blocked alerts", errors.len(), alerts.get());
+        for r in stock.iter().filter(|r| r.quantity > 0) {
+            println!("  {}: {}", world.registry.get(r.id).name, r.quantity);
+        }
+        return;
+    }
+    if let Some(speed) = flag_value("--speed") {
+        let Some(speed) = speed.parse().ok().filter(|speed: &f64| speed.is_finite()) else {
+            eprintln!("--speed expects a multiplier, got `{speed}`");
+            process::exit(1);
+        };
+        world.set_speed(speed);
+    }
+    if std::env::args().any(|arg| arg == "--paused") {
+        world.set_paused(true);
+    }
+    world.run();
+}
+
+/// The argument following `flag` on the command line, if `flag` was given.
+fn flag_value(flag: &str) -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != flag);
+    args.next()?;
+    Some(args.next().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> Registry {
+        Registry::load(RESOURCE_DEFINITIONS).expect("resource definitions load")
+    }
+
+    /// The demo factory plus a sink eating one random unit a tick from two slow links, so
+    /// that what it eats depends on the order deliveries arrive in.
+    fn demo(seed: u64) -> World {
+        let mut world = World::new(registry(), seed);
+        setup_chain(&mut world).expect("demo scenario sets up");
+        let (ore, coal) = (world.registry.resource("iron_ore", 1), world.registry.resource("coal", 1));
+        let entities = &mut world.entities;
+        entities.policy = ConnectionPolicy::Manual;
+        let place = |entities: &mut Entities, kind, wants, x| {
+            entities.place(kind, wants, Inventory::new(), Placement::new((x, 10), Direction::East), true).unwrap()
+        };
+        let ore_source = place(entities, EntityKind::Source { produces: ore }, Inventory::new(), 10);
+        let coal_source = place(entities, EntityKind::Source { produces: coal }, Inventory::new(), 11);
+        let sink = place(entities, EntityKind::Sink, Inventory::from([ore, coal]), 12);
+        for source in [ore_source, coal_source] {
+            entities.connect(source, sink).unwrap();
+            entities.set_link(source, sink, None, 2).unwrap();
+        }
+        entities.set_demand(sink, 1, 1).unwrap();
+        world
+    }
+
+    /// Everything that changes from tick to tick, per slot.
+    fn state(entities: &Entities) -> Vec<String> {
+        entities.live()
+            .map(|i| format!("{:?} {:?} {:?} {:?} {} {:?}", entities.has[i], entities.in_transit[i],
+                             entities.crafting[i], entities.work[i], entities.broken[i], entities.delivered[i]))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_replays_identically() {
+        let mut worlds: Vec<World> = (0..8).map(|_| demo(7)).collect();
+        for tick in 0..300 {
+            for world in &mut worlds {
+                world.run_headless(1, &mut Vec::new());
+            }
+            let expected = state(&worlds[0].entities);
+            for world in &worlds[1..] {
+                assert_eq!(state(&world.entities), expected, "diverged at tick {tick}");
+            }
+        }
+    }
+
+    #[test]
+    fn different_seeds_diverge() {
+        let (mut a, mut b) = (demo(1), demo(2));
+        let (a, b) = (a.run_headless(500, &mut Vec::new()), b.run_headless(500, &mut Vec::new()));
+        assert_ne!(state(a), state(b));
+    }
+
+    /// Two sources merge onto a belt into a storage that splits between two sinks, placed in
+    /// the given order. Returns each entity's ore and deliveries by position after 50 ticks.
+    fn buffered_fan(order: &[usize]) -> Vec<(Position, Quantity, Quantity)> {
+        let registry = registry();
+        let ore = |n| registry.resource("iron_ore", n);
+        let specs = [
+            (EntityKind::Source { produces: ore(3) }, Inventory::new(), (0, 0)),
+            (EntityKind::Source { produces: ore(2) }, Inventory::new(), (0, 2)),
+            (EntityKind::Conveyor { rate: 4 }, Inventory::new(), (1, 1)),
+            (EntityKind::Storage, Inventory::from([ore(5)]), (2, 1)),
+            (EntityKind::Sink, Inventory::from([ore(2)]), (3, 0)),
+            (EntityKind::Sink, Inventory::from([ore(2)]), (3, 2)),
+        ];
+        let mut entities = Entities::new((8, 8));
+        entities.policy = ConnectionPolicy::Manual;
+        entities.mode = UpdateMode::Buffered;
+        let mut ids = [None; 6];
+        for &n in order {
+            let (kind, wants, position) = specs[n].clone();
+            let placement = Placement::new(position, Direction::East);
+            ids[n] = Some(entities.place(kind, wants, Inventory::new(), placement, true).unwrap());
+        }
+        let id = |n: usize| ids[n].unwrap();
+        for (from, to) in [(0, 2), (1, 2), (2, 3), (3, 4), (3, 5)] {
+            entities.connect(id(from), id(to)).unwrap();
+        }
+        entities.set_distribution(id(3), Distribution::RoundRobin, Distribution::Proportional).unwrap();
+        let mut rng = Rng::new(DEFAULT_SEED);
+        for _ in 0..50 {
+            assert!(entities.update(&registry, &mut rng).is_empty());
+        }
+        let mut result: Vec<_> = entities.live()
+            .map(|i| (entities.position[i], entities.has[i].get(ore(0).id), entities.delivered[i].get(ore(0).id)))
+            .collect();
+        result.sort();
+        result
+    }
+
+    #[test]
+    fn buffered_ignores_insertion_order() {
+        let expected = buffered_fan(&[0, 1, 2, 3, 4, 5]);
+        assert!(expected.iter().any(|&(_, _, delivered)| delivered > 0));
+        for order in [[5, 4, 3, 2, 1, 0], [3, 0, 5, 2, 4, 1], [2, 5, 1, 4, 0, 3]] {
+            assert_eq!(buffered_fan(&order), expected, "insertion order {order:?}");
+        }
+    }
+
+    fn parse_error(source: &str) -> String {
+        Registry::parse(source).expect_err("definitions should be rejected").to_string()
+    }
+
+    #[test]
+    fn registry_parses_comments_and_hash_glyphs() {
+        let registry = Registry::parse("# header\n\nslag # white 50 # trailing comment\n  # indented\n").unwrap();
+        let slag = registry.id("slag").unwrap();
+        assert_eq!(registry.get(slag).glyph, '#');
+        assert_eq!(registry.get(slag).stack_size, 50);
+        assert_eq!(registry.iter().count(), 1);
+    }
+
+    #[test]
+    fn registry_rejects_bad_definitions() {
+        assert!(parse_error("iron o red").contains("line 1: expected 4 fields, found 3"));
+        assert!(parse_error("iron o red 10 extra").contains("expected 4 fields, found 5"));
+        assert!(parse_error("iron o red 10\niron i cyan 10").contains("line 2: duplicate resource `iron`"));
+        assert!(parse_error("iron oo red 10").contains("glyph `oo` must be a single character"));
+        assert!(parse_error("iron o mauve 10").contains("unknown color `mauve`"));
+        assert!(parse_error("iron o red ten").contains("invalid stack size `ten`"));
+        assert!(parse_error("iron o red -1").contains("invalid stack size `-1`"));
+    }
+
+    #[test]
+    fn mixed_latency_links_respect_capacity() {
+        let registry = registry();
+        let ore = |n| registry.resource("iron_ore", n);
+        let mut entities = Entities::new((8, 8));
+        entities.policy = ConnectionPolicy::Manual;
+        let place = |entities: &mut Entities, kind, wants, x| {
+            entities.place(kind, wants, Inventory::new(), Placement::new((x, 0), Direction::East), true).unwrap()
+        };
+        let near = place(&mut entities, EntityKind::Source { produces: ore(10) }, Inventory::new(), 0);
+        let far = place(&mut entities, EntityKind::Source { produces: ore(10) }, Inventory::new(), 1);
+        let storage = place(&mut entities, EntityKind::Storage, Inventory::from([ore(10)]), 2);
+        entities.connect(near, storage).unwrap();
+        entities.connect(far, storage).unwrap();
+        entities.set_link(far, storage, None, 2).unwrap();
+        entities.set_capacity(storage, 20).unwrap();
+        entities.set_distribution(storage, Distribution::Proportional, Distribution::RoundRobin).unwrap();
+        let mut rng = Rng::new(DEFAULT_SEED);
+        for _ in 0..10 {
+            entities.update(&registry, &mut rng);
+            assert!(entities.held(storage.index) <= 20, "storage holds {}", entities.held(storage.index));
+        }
+        assert_eq!(entities.has[storage.index].get(ore(0).id), 20);
+    }
+
+    #[test]
+    fn overstocked_source_keeps_its_stock() {
+        let registry = registry();
+        let coal = registry.resource("coal", 250);
+        let mut entities = Entities::new((8, 8));
+        let source = entities.place(EntityKind::Source { produces: Resource::new(coal.id, 1) }, Inventory::new(),
+                                    Inventory::from([coal]), Placement::new((0, 0), Direction::East), true).unwrap();
+        entities.update(&registry, &mut Rng::new(DEFAULT_SEED));
+        assert_eq!(entities.has[source.index].get(coal.id), 250);
+        assert_eq!(entities.ledger.produced.total(), 0);
+    }
+
+    /// Two belts side by side, `a` at (1, 1) facing East into `b` at (2, 1).
+    fn belt_pair(policy: ConnectionPolicy) -> (Entities, EntityId, EntityId) {
+        let mut entities = Entities::new((8, 8));
+        entities.policy = policy;
+        let mut belt = |x| entities.place(EntityKind::Conveyor { rate: 1 }, Inventory::new(), Inventory::new(),
+                                          Placement::new((x, 1), Direction::East), true).unwrap();
+        let (a, b) = (belt(1), belt(2));
+        (entities, a, b)
+    }
+
+    fn rotate_full_circle(entities: &mut Entities, id: EntityId) {
+        for _ in Direction::ALL {
+            entities.rotate(id).unwrap();
+        }
+    }
+
+    #[test]
+    fn rotation_keeps_explicit_links() {
+        let (mut entities, a, b) = belt_pair(ConnectionPolicy::Adjacent);
+        assert_eq!(entities.downstream[a.index], [b.index]);
+        entities.connect(b, a).unwrap();
+        entities.rotate(a).unwrap();
+        assert_eq!(entities.upstream[a.index], [b.index], "connected link survives rotation");
+        assert!(entities.downstream[a.index].is_empty(), "adjacency link follows the new facing");
+        entities.rotate(a).unwrap();
+        entities.rotate(a).unwrap();
+        entities.rotate(a).unwrap();
+        assert_eq!(entities.downstream[a.index], [b.index], "facing back east relinks");
+        entities.disconnect(a, b).unwrap();
+        rotate_full_circle(&mut entities, a);
+        assert!(entities.downstream[a.index].is_empty(), "disconnected pair stays cut");
+        entities.connect(a, b).unwrap();
+        assert_eq!(entities.downstream[a.index], [b.index]);
+    }
+
+    #[test]
+    fn manual_policy_rotation_does_not_rewire() {
+        let (mut entities, a, b) = belt_pair(ConnectionPolicy::Manual);
+        entities.connect(b, a).unwrap();
+        rotate_full_circle(&mut entities, a);
+        entities.rotate(a).unwrap();
+        assert_eq!(entities.upstream[a.index], [b.index]);
+        assert!(entities.downstream[a.index].is_empty());
+    }
+
+    #[test]
+    fn busy_assembler_is_not_blocked() {
+        let registry = registry();
+        let (ore, coal, plate) = (|n| registry.resource("iron_ore", n), |n| registry.resource("coal", n),
+                                  |n| registry.resource("iron_plate", n));
+        let smelt = Recipe::new("smelt iron", Inventory::from([ore(2), coal(1)]), Inventory::from([plate(1)]), 4);
+        let mut entities = Entities::new((8, 8));
+        let storage = entities.place(EntityKind::Storage, Inventory::new(), Inventory::from([ore(100), coal(100)]),
+                                     Placement::new((0, 0), Direction::East), true).unwrap();
+        let smelter = entities.place(EntityKind::Assembler(smelt.clone()), smelt.inputs, Inventory::new(),
+                                     Placement::new((1, 0), Direction::East), true).unwrap();
+        let sink = entities.place(EntityKind::Sink, Inventory::from([plate(1)]), Inventory::new(),
+                                  Placement::new((2, 0), Direction::East), true).unwrap();
+        assert_eq!(entities.downstream[storage.index], [smelter.index]);
+        assert_eq!(entities.downstream[smelter.index], [sink.index]);
+        let mut rng = Rng::new(DEFAULT_SEED);
+        for _ in 0..20 {
+            entities.update(&registry, &mut rng);
+            assert!(!entities.events.last().contains(&Event::Blocked { entity: smelter }));
+        }
+        assert!(entities.delivered[sink.index].get(plate(0).id) > 0);
+    }
+
+    #[test]
+    fn resource_arithmetic_reports_errors() {
+        let (ore, coal) = (ResourceId(0), ResourceId(1));
+        let mismatch = ResourceError::Mismatch { left: ore, right: coal };
+        assert_eq!(Resource::new(ore, 1).checked_add(Resource::new(coal, 1)), Err(mismatch));
+        assert_eq!(Resource::new(ore, 1).checked_sub(Resource::new(coal, 1)), Err(mismatch));
+        assert_eq!(Resource::new(ore, 1).try_transfer(&mut Resource::empty(coal), 1, 10), Err(mismatch));
+
+        let full = Resource::new(ore, Quantity::MAX);
+        assert_eq!(full.checked_add(Resource::new(ore, 1)),
+                   Err(ResourceError::Overflow { resource: full, amount: 1 }));
+        let few = Resource::new(ore, 2);
+        assert_eq!(few.checked_sub(Resource::new(ore, 3)),
+                   Err(ResourceError::Underflow { resource: few, amount: 3 }));
+        assert_eq!(few.checked_add(Resource::new(ore, 3)), Ok(Resource::new(ore, 5)));
+
+        let (mut from, mut to) = (Resource::new(ore, 5), Resource::new(ore, 8));
+        assert_eq!(from.try_transfer(&mut to, 4, 10), Ok(2));
+        assert_eq!((from.quantity, to.quantity), (3, 10));
+        let error: Box<dyn Error> = Box::new(mismatch);
+        assert_eq!(error.to_string(), "cannot combine resource #0 with resource #1");
+    }
+
+    /// Entities in a row, unlinked: a source first, then storages, then one structure.
+    fn row(storages: usize) -> (Entities, Vec<EntityId>) {
+        let registry = registry();
+        let mut entities = Entities::new((16, 4));
+        entities.policy = ConnectionPolicy::Manual;
+        let kinds = std::iter::once(EntityKind::Source { produces: registry.resource("iron_ore", 1) })
+            .chain(std::iter::repeat_n(EntityKind::Storage, storages))
+            .chain(std::iter::once(EntityKind::Structure));
+        let ids = kinds.enumerate()
+            .map(|(x, kind)| entities.place(kind, Inventory::new(), Inventory::new(),
+                                            Placement::new((x as isize, 0), Direction::East), true).unwrap())
+            .collect();
+        (entities, ids)
+    }
+
+    #[test]
+    fn analysis_of_a_graph_with_a_cycle() {
+        let (mut entities, id) = row(3);
+        // source -> 1 <-> 2, with 3 fed only from the loop and the structure on its own.
+        for (from, to) in [(0, 1), (1, 2), (2, 1), (2, 3)] {
+            entities.connect(id[from], id[to]).unwrap();
+        }
+        let analysis = Analysis::run(&entities);
+        assert_eq!(analysis.cycles, [vec![id[1], id[2]]]);
+        assert_eq!(analysis.components, [vec![id[0], id[1], id[2], id[3]], vec![id[4]]]);
+        assert!(analysis.unsupplied.is_empty());
+        assert_eq!(analysis.order, None);
+    }
+
+    #[test]
+    fn analysis_of_a_diamond() {
+        let (mut entities, id) = row(4);
+        // source -> 1 -> 3 and source -> 2 -> 3, with 4 left unlinked.
+        for (from, to) in [(0, 2), (0, 1), (2, 3), (1, 3)] {
+            entities.connect(id[from], id[to]).unwrap();
+        }
+        let analysis = Analysis::run(&entities);
+        assert!(analysis.cycles.is_empty());
+        assert_eq!(analysis.components, [vec![id[0], id[1], id[2], id[3]], vec![id[4]], vec![id[5]]]);
+        assert_eq!(analysis.unsupplied, [id[4]], "structures need no supply");
+        let order = analysis.order.expect("a diamond has no cycle");
+        assert_eq!(order.len(), id.len());
+        let rank = |n: usize| order.iter().position(|&o| o == id[n]).unwrap();
+        assert!(rank(0) < rank(1) && rank(0) < rank(2) && rank(1) < rank(3) && rank(2) < rank(3));
+    }
+
+    #[test]
+    fn removal_unlinks_and_stales_the_handle() {
+        let (mut entities, id) = row(2);
+        entities.connect(id[0], id[1]).unwrap();
+        entities.connect(id[1], id[2]).unwrap();
+        let ore = registry().resource("iron_ore", 7);
+        entities.has[id[1].index] = Inventory::from([ore]);
+
+        let held = entities.remove(id[1]).unwrap();
+        assert_eq!(held.get(ore.id), 7);
+        assert!(entities.downstream[id[0].index].is_empty());
+        assert!(entities.upstream[id[2].index].is_empty());
+        assert!(matches!(entities.resolve(id[1]), Err(EntityError::Stale(stale)) if stale == id[1]));
+
+        let reused = entities.place(EntityKind::Storage, Inventory::new(), Inventory::new(),
+                                    Placement::new((1, 0), Direction::East), true).unwrap();
+        assert_eq!(reused.index, id[1].index);
+        assert_ne!(reused.generation, id[1].generation);
+        assert!(entities.upstream[reused.index].is_empty() && entities.downstream[reused.index].is_empty());
+        assert!(matches!(entities.resolve(id[1]), Err(EntityError::Stale(_))));
+        assert!(matches!(entities.remove(id[1]), Err(EntityError::Stale(_))));
+        assert!(matches!(entities.connect(id[0], id[1]), Err(EntityError::Stale(_))));
+        assert_eq!(entities.resolve(reused).unwrap(), reused.index);
+        let unknown = EntityId { index: 99, generation: 0 };
+        assert!(matches!(entities.resolve(unknown), Err(EntityError::Unknown(_))));
+    }
+
+    #[test]
+    fn oversized_placement_is_out_of_bounds() {
+        let entities = Entities::new((8, 8));
+        let placement = Placement::new((1, 1), Direction::East).with_size(usize::MAX, 1);
+        assert!(matches!(entities.check_placement(placement), Err(PlacementError::OutOfBounds { .. })));
     }
 }
@@ -0,0 +1,128 @@
+use std::fmt;
+
+use crate::Position;
+
+/// An entity's part in the power layer.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PowerRole {
+    None,
+    /// Supplies `output` units of power to its network every tick.
+    Generator { output: u32 },
+    /// Needs `demand` units of power every tick to run at full speed.
+    Consumer { demand: u32 },
+    /// Links every pole, generator and consumer within `range` cells into one network.
+    Pole { range: u32 },
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Network {
+    pub supply: u32,
+    pub demand: u32,
+}
+
+impl Network {
+    /// Fraction of demand that is met, from 0.0 (stopped) to 1.0 (full speed).
+    pub fn satisfaction(&self) -> f32 {
+        if self.demand == 0 {
+            1.0
+        } else {
+            (self.supply as f32 / self.demand as f32).min(1.0)
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{} ({:.0}%)", self.supply, self.demand, self.satisfaction() * 100.0)
+    }
+}
+
+/// The solved power layer for one tick.
+#[derive(Debug, Clone, Default)]
+pub struct PowerGrid {
+    pub networks: Vec<Network>,
+    /// Speed multiplier per entity. Entities that need no power always run at 1.0; consumers
+    /// outside every network get 0.0.
+    pub speed: Vec<f32>,
+}
+
+fn distance(a: Position, b: Position) -> u32 {
+    (a.0 - b.0).unsigned_abs().max((a.1 - b.1).unsigned_abs()) as u32
+}
+
+fn find(parent: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    parent[i] = root;
+    root
+}
+
+impl PowerGrid {
+    /// Groups poles that reach each other into networks, attaches generators and consumers to
+    /// the first pole that reaches them, then balances supply against demand per network.
+    pub fn solve(roles: &[PowerRole], positions: &[Position]) -> Self {
+        let poles: Vec<(usize, u32)> = roles.iter().enumerate()
+            .filter_map(|(i, role)| match role {
+                PowerRole::Pole { range } => Some((i, *range)),
+                _ => None,
+            })
+            .collect();
+
+        let mut parent: Vec<usize> = (0..poles.len()).collect();
+        for a in 0..poles.len() {
+            for b in (a + 1)..poles.len() {
+                let reach = poles[a].1.max(poles[b].1);
+                if distance(positions[poles[a].0], positions[poles[b].0]) <= reach {
+                    let (ra, rb) = (find(&mut parent, a), find(&mut parent, b));
+                    parent[ra] = rb;
+                }
+            }
+        }
+
+        let mut network_of_root = vec![None; poles.len()];
+        let mut networks = Vec::new();
+        for p in 0..poles.len() {
+            let root = find(&mut parent, p);
+            if network_of_root[root].is_none() {
+                network_of_root[root] = Some(networks.len());
+                networks.push(Network::default());
+            }
+        }
+        let network_of = |i: usize, parent: &mut [usize]| {
+            poles.iter().enumerate()
+                .find(|(_, (pole, range))| distance(positions[*pole], positions[i]) <= *range)
+                .and_then(|(p, _)| network_of_root[find(parent, p)])
+        };
+
+        let mut membership = vec![None; roles.len()];
+        for (i, role) in roles.iter().enumerate() {
+            match role {
+                PowerRole::Generator { output } => {
+                    membership[i] = network_of(i, &mut parent);
+                    if let Some(n) = membership[i] {
+                        networks[n].supply += output;
+                    }
+                }
+                PowerRole::Consumer { demand } => {
+                    membership[i] = network_of(i, &mut parent);
+                    if let Some(n) = membership[i] {
+                        networks[n].demand += demand;
+                    }
+                }
+                PowerRole::None | PowerRole::Pole { .. } => {}
+            }
+        }
+
+        let speed = roles.iter().zip(&membership)
+            .map(|(role, network)| match (role, network) {
+                (PowerRole::Consumer { .. }, Some(n)) => networks[*n].satisfaction(),
+                (PowerRole::Consumer { .. }, None) => 0.0,
+                _ => 1.0,
+            })
+            .collect();
+
+        Self { networks, speed }
+    }
+}
@@ -0,0 +1,24 @@
+use crate::resource::Inventory;
+
+/// A transformation performed by an assembler: consume `inputs`, wait `duration` ticks,
+/// give or take up to `jitter`, then produce `outputs`.
+#[derive(Debug, Clone)]
+pub struct Recipe {
+    pub name: String,
+    pub inputs: Inventory,
+    pub outputs: Inventory,
+    pub duration: u32,
+    pub jitter: u32,
+}
+
+impl Recipe {
+    pub fn new(name: &str, inputs: Inventory, outputs: Inventory, duration: u32) -> Self {
+        Self { name: name.to_string(), inputs, outputs, duration, jitter: 0 }
+    }
+
+    /// Varies each craft's duration randomly by up to `jitter` ticks either way.
+    pub fn with_jitter(mut self, jitter: u32) -> Self {
+        self.jitter = jitter;
+        self
+    }
+}
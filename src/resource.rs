@@ -0,0 +1,303 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Index of a resource definition inside a `Registry`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ResourceId(pub usize);
+
+/// Unit count used for every stockpile; change this alias to widen or narrow all of them.
+pub type Quantity = u32;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Resource {
+    pub id: ResourceId,
+    pub quantity: Quantity,
+}
+
+impl Resource {
+    pub fn new(id: ResourceId, quantity: Quantity) -> Self {
+        Self { id, quantity }
+    }
+
+    pub fn empty(id: ResourceId) -> Self {
+        Self::new(id, 0)
+    }
+
+    fn check_kind(self, other: Self) -> Result<(), ResourceError> {
+        if self.id == other.id {
+            Ok(())
+        } else {
+            Err(ResourceError::Mismatch { left: self.id, right: other.id })
+        }
+    }
+
+    pub fn checked_add(self, other: Self) -> Result<Self, ResourceError> {
+        self.check_kind(other)?;
+        match self.quantity.checked_add(other.quantity) {
+            Some(quantity) => Ok(Self::new(self.id, quantity)),
+            None => Err(ResourceError::Overflow { resource: self, amount: other.quantity }),
+        }
+    }
+
+    pub fn checked_sub(self, other: Self) -> Result<Self, ResourceError> {
+        self.check_kind(other)?;
+        match self.quantity.checked_sub(other.quantity) {
+            Some(quantity) => Ok(Self::new(self.id, quantity)),
+            None => Err(ResourceError::Underflow { resource: self, amount: other.quantity }),
+        }
+    }
+
+    /// Moves up to `quantity` units from `self` into `to`, limited by what `self` holds and
+    /// by `to` not exceeding `limit`. Returns the number of units moved.
+    pub fn try_transfer(&mut self, to: &mut Self, quantity: Quantity, limit: Quantity) -> Result<Quantity, ResourceError> {
+        self.check_kind(*to)?;
+        let moved = quantity
+            .min(self.quantity)
+            .min(limit.saturating_sub(to.quantity));
+        let moved_resource = Self::new(self.id, moved);
+        let source = self.checked_sub(moved_resource)?;
+        *to = to.checked_add(moved_resource)?;
+        *self = source;
+        Ok(moved)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResourceError {
+    Mismatch { left: ResourceId, right: ResourceId },
+    Overflow { resource: Resource, amount: Quantity },
+    Underflow { resource: Resource, amount: Quantity },
+}
+
+impl fmt::Display for ResourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Mismatch { left, right } =>
+                write!(f, "cannot combine resource #{} with resource #{}", left.0, right.0),
+            Self::Overflow { resource, amount } =>
+                write!(f, "adding {amount} to {} units of resource #{} overflows", resource.quantity, resource.id.0),
+            Self::Underflow { resource, amount } =>
+                write!(f, "removing {amount} from {} units of resource #{} underflows", resource.quantity, resource.id.0),
+        }
+    }
+}
+
+impl Error for ResourceError {}
+
+impl PartialOrd for Resource {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        if self.id == other.id {
+            self.quantity.partial_cmp(&other.quantity)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ResourceDef {
+    pub name: String,
+    pub glyph: char,
+    /// ANSI foreground color code (30-37).
+    pub color: u8,
+    pub stack_size: Quantity,
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    Io(io::Error),
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "could not read resource definitions: {e}"),
+            Self::Parse { line, message } => write!(f, "resource definitions line {line}: {message}"),
+        }
+    }
+}
+
+impl Error for RegistryError {}
+
+impl From<io::Error> for RegistryError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// The set of resource kinds known to a world, loaded from a definitions file.
+///
+/// Each non-empty, non-comment line of the file reads `name glyph color stack_size`, e.g.
+/// `iron_ore o red 200`. Comments start with `#`, either as the first character of a line or
+/// after the four fields, so `#` itself can still be a glyph.
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    defs: Vec<ResourceDef>,
+}
+
+impl Registry {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, RegistryError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(source: &str) -> Result<Self, RegistryError> {
+        let mut registry = Self::default();
+        for (n, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: String| RegistryError::Parse { line: n + 1, message };
+            let mut fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.get(4).is_some_and(|field| field.starts_with('#')) {
+                fields.truncate(4);
+            }
+            let [name, glyph, color, stack_size] = fields[..] else {
+                return Err(error(format!("expected 4 fields, found {}", fields.len())));
+            };
+            if registry.id(name).is_some() {
+                return Err(error(format!("duplicate resource `{name}`")));
+            }
+            let mut chars = glyph.chars();
+            let glyph = match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(error(format!("glyph `{glyph}` must be a single character"))),
+            };
+            let color = parse_color(color).ok_or_else(|| error(format!("unknown color `{color}`")))?;
+            let stack_size = stack_size.parse()
+                .map_err(|e| error(format!("invalid stack size `{stack_size}`: {e}")))?;
+            registry.defs.push(ResourceDef { name: name.to_string(), glyph, color, stack_size });
+        }
+        Ok(registry)
+    }
+
+    pub fn id(&self, name: &str) -> Option<ResourceId> {
+        self.defs.iter().position(|d| d.name == name).map(ResourceId)
+    }
+
+    /// Builds a resource by name, panicking if it is not defined.
+    pub fn resource(&self, name: &str, quantity: Quantity) -> Resource {
+        let id = self.id(name).unwrap_or_else(|| panic!("Unknown resource `{name}`"));
+        Resource::new(id, quantity)
+    }
+
+    pub fn get(&self, id: ResourceId) -> &ResourceDef {
+        &self.defs[id.0]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ResourceId, &ResourceDef)> {
+        self.defs.iter().enumerate().map(|(i, d)| (ResourceId(i), d))
+    }
+}
+
+fn parse_color(name: &str) -> Option<u8> {
+    let code = match name {
+        "black" => 30,
+        "red" => 31,
+        "green" => 32,
+        "yellow" => 33,
+        "blue" => 34,
+        "magenta" => 35,
+        "cyan" => 36,
+        "white" => 37,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Which resource kinds an entity accepts from upstream.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ResourceFilter {
+    #[default]
+    Any,
+    Only(Vec<ResourceId>),
+}
+
+impl ResourceFilter {
+    pub fn allows(&self, id: ResourceId) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Only(ids) => ids.contains(&id),
+        }
+    }
+}
+
+/// A bag of resources holding at most one stack per resource kind.
+#[derive(Debug, Clone, Default)]
+pub struct Inventory {
+    items: Vec<Resource>,
+}
+
+impl Inventory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, id: ResourceId) -> Quantity {
+        self.items.iter().find(|r| r.id == id).map_or(0, |r| r.quantity)
+    }
+
+    pub fn get_mut(&mut self, id: ResourceId) -> Option<&mut Resource> {
+        self.items.iter_mut().find(|r| r.id == id)
+    }
+
+    /// The stack for `id`, created empty if the inventory does not hold that kind yet.
+    pub fn entry(&mut self, id: ResourceId) -> &mut Resource {
+        match self.items.iter().position(|r| r.id == id) {
+            Some(i) => &mut self.items[i],
+            None => {
+                self.items.push(Resource::empty(id));
+                self.items.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// Adds `resource` to its stack, saturating at the quantity type's maximum. Returns how
+    /// many units did not fit.
+    pub fn add(&mut self, resource: Resource) -> Quantity {
+        let stack = self.entry(resource.id);
+        let before = stack.quantity;
+        stack.quantity = stack.quantity.saturating_add(resource.quantity);
+        resource.quantity - (stack.quantity - before)
+    }
+
+    /// Whether every stack in `other` is covered by this inventory.
+    pub fn contains(&self, other: &Inventory) -> bool {
+        other.iter().all(|r| self.get(r.id) >= r.quantity)
+    }
+
+    /// Removes every stack in `other`, which the caller must have checked with `contains`.
+    pub fn remove(&mut self, other: &Inventory) {
+        for r in other.iter() {
+            let stack = self.entry(r.id);
+            stack.quantity = stack.quantity.saturating_sub(r.quantity);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Resource> {
+        self.items.iter()
+    }
+
+    pub fn total(&self) -> Quantity {
+        self.items.iter().map(|r| r.quantity).sum()
+    }
+
+    /// The largest single stack, used to pick a display band and glyph.
+    pub fn largest(&self) -> Option<Resource> {
+        self.items.iter().copied().filter(|r| r.quantity > 0).max_by_key(|r| r.quantity)
+    }
+}
+
+impl<const N: usize> From<[Resource; N]> for Inventory {
+    fn from(resources: [Resource; N]) -> Self {
+        let mut inventory = Self::new();
+        for r in resources {
+            inventory.add(r);
+        }
+        inventory
+    }
+}
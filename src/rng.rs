@@ -0,0 +1,38 @@
+/// A small seeded pseudo-random generator (SplitMix64). Every stochastic part of the
+/// simulation draws from the one owned by `World`, so a seed replays a run exactly.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A uniform float in `0.0..1.0`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// True with probability `p`.
+    pub fn chance(&mut self, p: f64) -> bool {
+        self.next_f64() < p
+    }
+
+    /// A uniform integer in `low..=high`.
+    pub fn range(&mut self, low: u32, high: u32) -> u32 {
+        if high <= low {
+            return low;
+        }
+        low + (self.next_u64() % ((high - low) as u64 + 1)) as u32
+    }
+}
@@ -0,0 +1,27 @@
+use std::collections::HashMap;
+
+use crate::Position;
+
+/// Maps occupied cells to the entity slot covering them, so lookups by position do not scan
+/// every entity.
+#[derive(Debug, Default)]
+pub struct SpatialIndex {
+    cells: HashMap<Position, usize>,
+}
+
+impl SpatialIndex {
+    pub fn insert(&mut self, position: Position, i: usize) {
+        self.cells.insert(position, i);
+    }
+
+    /// Clears `position` if it is still owned by slot `i`.
+    pub fn remove(&mut self, position: Position, i: usize) {
+        if self.cells.get(&position) == Some(&i) {
+            self.cells.remove(&position);
+        }
+    }
+
+    pub fn get(&self, position: Position) -> Option<usize> {
+        self.cells.get(&position).copied()
+    }
+}